@@ -12,6 +12,12 @@ struct FnArgInfo {
 
 const ROUTE_NAME: &str = "Route";
 const QUERY_NAME: &str = "Query";
+const HEADERS_NAME: &str = "Headers";
+const PATH_NAME: &str = "Path";
+const QUERY_OF_NAME: &str = "QueryOf";
+const STATE_NAME: &str = "State";
+const CLIENT_CERTIFICATE_NAME: &str = "ClientCertificate";
+const BYTES_NAME: &str = "Bytes";
 
 #[proc_macro_attribute]
 pub fn http_handler(args: TokenStream, item: TokenStream) -> TokenStream {
@@ -34,24 +40,55 @@ pub fn http_handler(args: TokenStream, item: TokenStream) -> TokenStream {
     let query_arg = quote! { query: Query }.into();
     let query_arg = parse_macro_input!(query_arg as syn::FnArg);
 
-    let args_quote = build_args_quote(&args, route_arg, query_arg);
+    let headers_arg = quote! { headers: sugondese::headers::Headers }.into();
+    let headers_arg = parse_macro_input!(headers_arg as syn::FnArg);
+
+    let args_quote = build_args_quote(&args, route_arg, query_arg, headers_arg);
+    let extractors_quote = get_extractors_quote(&args);
     let body_quote = get_body_quote(&args);
 
     let response_mapping = map_response();
+    let request_destructuring = quote! {
+        let sugondese::handler::HandlerRequest { route, query, headers, state, body, client_certificate } = request;
+    };
+
+    let body_check_quote = body_quote.as_ref().map(|_| {
+        quote! {
+            if body.is_none() {
+                return sugondese::http_response::HttpResponse::new(
+                    sugondese::http_response::HttpStatus::BadRequest,
+                    Some("body missing".to_string())
+                );
+            }
+        }
+    });
+
+    let wrapper_body = quote! {
+        #request_destructuring
+        #body_check_quote
+        #extractors_quote
+        #body_quote
+    };
 
-    if body_quote.is_none() {
+    // `async fn` handlers get a wrapper returning the future, awaited by the async server.
+    if input.sig.asyncness.is_some() {
         return quote! {
             fn #handler_ident() -> sugondese::http_handler_info::HttpHandlerInfo {
                 return sugondese::http_handler_info::HttpHandlerInfo {
-                    handler: #wrapper_handler_ident,
+                    handler: std::sync::Arc::new(sugondese::handler::AsyncHandler(#wrapper_handler_ident)),
                     route: #route.to_string(),
                 };
             }
 
-            fn #wrapper_handler_ident(route: Route, query: Query, _body_string: Option<String>) -> sugondese::http_response::HttpResponse {
-                let result = #original_handler_ident(#args_quote);
+            fn #wrapper_handler_ident(
+                request: sugondese::handler::HandlerRequest,
+            ) -> impl std::future::Future<Output = sugondese::http_response::HttpResponse> + Send + 'static {
+                async move {
+                    #wrapper_body
+                    let result = #original_handler_ident(#args_quote).await;
 
-                #response_mapping
+                    #response_mapping
+                }
             }
 
             #original_handler
@@ -62,20 +99,13 @@ pub fn http_handler(args: TokenStream, item: TokenStream) -> TokenStream {
     quote! {
         fn #handler_ident() -> sugondese::http_handler_info::HttpHandlerInfo {
             return sugondese::http_handler_info::HttpHandlerInfo {
-                handler: #wrapper_handler_ident,
+                handler: std::sync::Arc::new(#wrapper_handler_ident),
                 route: #route.to_string(),
             };
         }
 
-        fn #wrapper_handler_ident(route: Route, query: Query, body_string: Option<String>) -> sugondese::http_response::HttpResponse {
-            if body_string.is_none() {
-                return sugondese::http_response::HttpResponse {
-                    status: sugondese::http_response::HttpStatus::BadRequest,
-                    body: Some("body missing".to_string())
-                };
-            }
-
-            #body_quote
+        fn #wrapper_handler_ident(request: sugondese::handler::HandlerRequest) -> sugondese::http_response::HttpResponse {
+            #wrapper_body
             let result = #original_handler_ident(#args_quote);
 
             #response_mapping
@@ -88,59 +118,100 @@ pub fn http_handler(args: TokenStream, item: TokenStream) -> TokenStream {
 
 fn map_response() -> proc_macro2::TokenStream {
     quote! {
-        let body_string = if result.data.is_some() {
-            Some(serde_json::to_string(&result.data.unwrap()).unwrap())
-        }
-        else {
-            None
-        };
-
-        sugondese::http_response::HttpResponse {
-            status: result.status,
-            body: body_string
-        }
+        sugondese::http_response::IntoHttpResponse::into_http_response(result)
     }
 }
 
-fn get_body_quote(args_types_names: &Vec<FnArgInfo>) -> Option<proc_macro2::TokenStream> {
-    let body_arg_type_name = args_types_names
-        .iter()
-        .find(|&a| a.name != ROUTE_NAME && a.name != QUERY_NAME);
-
-    if body_arg_type_name.is_none() {
-        return None;
-    }
+fn get_body_quote(args_types_names: &[FnArgInfo]) -> Option<proc_macro2::TokenStream> {
+    let body_arg_type_name = args_types_names.iter().find(|&a| !is_extractor(&a.name))?;
 
-    let body_type = extract_arg_type(body_arg_type_name.unwrap().arg.clone());
+    let body_type = extract_arg_type(body_arg_type_name.arg.clone());
 
     Some(quote! {
-        let body_obj: serde_json::Result<#body_type> = serde_json::from_str(&body_string.unwrap());
+        let body_obj: serde_json::Result<#body_type> = serde_json::from_slice(&body.unwrap());
 
         if body_obj.is_err() {
-            return sugondese::http_response::HttpResponse {
-                status: sugondese::http_response::HttpStatus::BadRequest,
-                body: Some("Unable to deserialize body".to_string())
-            };
+            return sugondese::http_response::HttpResponse::new(
+                sugondese::http_response::HttpStatus::BadRequest,
+                Some("Unable to deserialize body".to_string())
+            );
         }
 
         let body_obj = body_obj.unwrap();
     })
 }
 
+/// Typed extractors are converted before the handler is called, params conversion
+/// errors are answered with 400, missing client certificate with 401 and missing state
+/// with 500. `Bytes` takes the raw body and can't fail.
+fn get_extractors_quote(args_types_names: &[FnArgInfo]) -> proc_macro2::TokenStream {
+    let mut result = quote! {};
+
+    for (idx, arg_info) in args_types_names.iter().enumerate() {
+        if arg_info.name == BYTES_NAME {
+            let arg_type = extract_arg_type(arg_info.arg.clone());
+            let arg_ident = get_extracted_arg_ident(idx);
+
+            result = quote! {
+                #result
+
+                let #arg_ident = <#arg_type>::from_body(body);
+            };
+
+            continue;
+        }
+
+        let (conversion, error_status) = match arg_info.name.as_str() {
+            PATH_NAME => (quote! { from_route(&route) }, quote! { BadRequest }),
+            QUERY_OF_NAME => (quote! { from_query(&query) }, quote! { BadRequest }),
+            STATE_NAME => (
+                quote! { from_app_state(&state) },
+                quote! { InternalServerError },
+            ),
+            CLIENT_CERTIFICATE_NAME => (
+                quote! { from_client_certificate(&client_certificate) },
+                quote! { Unauthorized },
+            ),
+            _ => continue,
+        };
+
+        let arg_type = extract_arg_type(arg_info.arg.clone());
+        let arg_ident = get_extracted_arg_ident(idx);
+
+        result = quote! {
+            #result
+
+            let #arg_ident = match <#arg_type>::#conversion {
+                Ok(value) => value,
+                Err(e) => {
+                    return sugondese::http_response::HttpResponse::new(
+                        sugondese::http_response::HttpStatus::#error_status,
+                        Some(e.to_string())
+                    );
+                }
+            };
+        };
+    }
+
+    result
+}
+
 fn build_args_quote(
-    args_types_names: &Vec<FnArgInfo>,
+    args_types_names: &[FnArgInfo],
     route_arg: FnArg,
     query_arg: FnArg,
+    headers_arg: FnArg,
 ) -> proc_macro2::TokenStream {
     let mut result = quote! {};
 
-    if args_types_names.len() == 0 {
+    if args_types_names.is_empty() {
         return result;
     }
 
+    // `Bytes` takes the body as well, so it can't be combined with a deserialized one.
     let body_args_count = args_types_names
         .iter()
-        .filter(|a| a.name != ROUTE_NAME && a.name != QUERY_NAME)
+        .filter(|a| !is_extractor(&a.name) || a.name == BYTES_NAME)
         .count();
 
     if body_args_count > 1 {
@@ -148,7 +219,13 @@ fn build_args_quote(
     }
 
     for (idx, arg_info) in args_types_names.iter().enumerate() {
-        let arg = get_arg_quote(arg_info.clone(), route_arg.clone(), query_arg.clone());
+        let arg = get_arg_quote(
+            arg_info.clone(),
+            idx,
+            route_arg.clone(),
+            query_arg.clone(),
+            headers_arg.clone(),
+        );
 
         result = if idx == 0 {
             quote! { #arg }
@@ -162,22 +239,55 @@ fn build_args_quote(
 
 fn get_arg_quote(
     arg_info: FnArgInfo,
+    idx: usize,
     route_arg: FnArg,
     query_arg: FnArg,
+    headers_arg: FnArg,
 ) -> proc_macro2::TokenStream {
     match &arg_info.name {
-        name if name == &ROUTE_NAME => {
+        name if name == ROUTE_NAME => {
             let pat = extract_arg_pat(route_arg);
-            return quote! { #pat };
+            quote! { #pat }
         }
-        name if name == &QUERY_NAME => {
+        name if name == QUERY_NAME => {
             let pat = extract_arg_pat(query_arg);
-            return quote! { #pat };
+            quote! { #pat }
+        }
+        name if name == HEADERS_NAME => {
+            let pat = extract_arg_pat(headers_arg);
+            quote! { #pat }
+        }
+        name if name == PATH_NAME
+            || name == QUERY_OF_NAME
+            || name == STATE_NAME
+            || name == CLIENT_CERTIFICATE_NAME
+            || name == BYTES_NAME =>
+        {
+            let ident = get_extracted_arg_ident(idx);
+            quote! { #ident }
         }
         _ => quote! { body_obj },
     }
 }
 
+fn get_extracted_arg_ident(idx: usize) -> proc_macro2::Ident {
+    format_ident!("extracted_arg_{}", idx)
+}
+
+fn is_extractor(name: &str) -> bool {
+    [
+        ROUTE_NAME,
+        QUERY_NAME,
+        HEADERS_NAME,
+        PATH_NAME,
+        QUERY_OF_NAME,
+        STATE_NAME,
+        CLIENT_CERTIFICATE_NAME,
+        BYTES_NAME,
+    ]
+    .contains(&name)
+}
+
 //fn get_body_fn_arg()
 
 fn get_args_types_names(args: &Punctuated<FnArg, Comma>) -> Vec<FnArgInfo> {
@@ -199,9 +309,11 @@ fn rename_original_handler(input: &ItemFn) -> ItemFn {
     let new_fn_ident = format_ident!("_{}", fn_ident);
     input_clone.sig.ident = new_fn_ident;
 
-    return input_clone;
+    input_clone
 }
 
+/// Extractors are recognized by their name when imported (`Path<T>`) or by their full
+/// path (`sugondese::extract::Path<T>`), any other path like `my::State` is the body.
 fn get_fn_arg_type(arg: &FnArg) -> String {
     let arg_type = extract_arg_type(arg.clone());
 
@@ -209,9 +321,32 @@ fn get_fn_arg_type(arg: &FnArg) -> String {
         Type::Path(val) => val.path,
         _ => panic!("Arg type not implemented"),
     };
-    let segment = path.segments.iter().next().unwrap();
+    let segments: Vec<String> = path
+        .segments
+        .iter()
+        .map(|segment| segment.ident.to_string())
+        .collect();
+
+    match segments.as_slice() {
+        [name] => name.clone(),
+        [krate, module, name]
+            if krate == "sugondese" && get_extractor_module(name) == Some(module.as_str()) =>
+        {
+            name.clone()
+        }
+        _ => segments.join("::"),
+    }
+}
 
-    segment.ident.to_string()
+fn get_extractor_module(name: &str) -> Option<&'static str> {
+    match name {
+        ROUTE_NAME | QUERY_NAME => Some("uri_params"),
+        HEADERS_NAME => Some("headers"),
+        PATH_NAME | QUERY_OF_NAME | STATE_NAME | CLIENT_CERTIFICATE_NAME | BYTES_NAME => {
+            Some("extract")
+        }
+        _ => None,
+    }
 }
 
 fn extract_arg_pat(a: FnArg) -> Box<Pat> {
@@ -227,3 +362,35 @@ fn extract_arg_type(a: FnArg) -> Box<Type> {
         _ => panic!("Not supported argument type"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{get_fn_arg_type, is_extractor};
+    use syn::{parse_quote, FnArg};
+
+    fn arg_type_name(arg: FnArg) -> String {
+        get_fn_arg_type(&arg)
+    }
+
+    #[test]
+    fn extractors_are_matched_by_name_or_full_path() {
+        assert_eq!(arg_type_name(parse_quote! { state: State<App> }), "State");
+        assert_eq!(
+            arg_type_name(parse_quote! { state: sugondese::extract::State<App> }),
+            "State"
+        );
+        assert_eq!(
+            arg_type_name(parse_quote! { route: sugondese::uri_params::Route }),
+            "Route"
+        );
+    }
+
+    #[test]
+    fn body_types_named_like_extractors_are_the_body() {
+        let name = arg_type_name(parse_quote! { body: my::State });
+        assert!(!is_extractor(&name));
+
+        let name = arg_type_name(parse_quote! { body: sugondese::headers::Path<Id> });
+        assert!(!is_extractor(&name));
+    }
+}
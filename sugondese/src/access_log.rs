@@ -0,0 +1,219 @@
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+/// `log` target of the access log lines, so they can be filtered or routed separately
+/// from the rest of the server logs.
+pub const ACCESS_LOG_TARGET: &str = "sugondese::access";
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Format of the access log line emitted for every served request.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AccessLogFormat {
+    /// Common Log Format: `127.0.0.1 - - [17/Oct/2026:10:00:00 +0000] "GET / HTTP/1.1" 200 5`.
+    Common,
+    /// Common Log Format followed by the quoted `Referer` and `User-Agent` headers.
+    Combined,
+    /// JSON object which also has the matched route template and the latency.
+    Json,
+}
+
+pub(crate) struct AccessLogEntry<'a> {
+    pub time: SystemTime,
+    pub client: Option<SocketAddr>,
+    /// `None` for requests rejected before their request line was parsed.
+    pub request_line: Option<RequestLine<'a>>,
+    pub route: Option<&'a str>,
+    pub status: u16,
+    pub bytes: usize,
+    pub latency: Duration,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+}
+
+pub(crate) struct RequestLine<'a> {
+    pub method: &'a str,
+    pub uri: &'a str,
+    pub version: &'a str,
+}
+
+impl AccessLogEntry<'_> {
+    pub fn format(&self, format: AccessLogFormat) -> String {
+        let client = self
+            .client
+            .map(|client| client.ip().to_string())
+            .unwrap_or_else(|| "-".to_string());
+
+        let request_line = self.request_line.as_ref();
+
+        if format == AccessLogFormat::Json {
+            return json!({
+                "time": format_rfc3339(self.time),
+                "client": client,
+                "method": request_line.map(|line| line.method),
+                "path": request_line.map(|line| line.uri),
+                "version": request_line.map(|line| line.version),
+                "route": self.route,
+                "status": self.status,
+                "bytes": self.bytes,
+                "latency_ms": self.latency.as_secs_f64() * 1000.0,
+                "referer": self.referer,
+                "user_agent": self.user_agent,
+            })
+            .to_string();
+        }
+
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+
+        let request_line = match request_line {
+            Some(line) => format!("{} {} {}", line.method, escape(line.uri), line.version),
+            None => "-".to_string(),
+        };
+
+        let common = format!(
+            "{client} - - [{}] \"{request_line}\" {} {bytes}",
+            format_clf_time(self.time),
+            self.status,
+        );
+
+        if format == AccessLogFormat::Common {
+            return common;
+        }
+
+        format!(
+            "{common} \"{}\" \"{}\"",
+            escape(self.referer.unwrap_or("-")),
+            escape(self.user_agent.unwrap_or("-"))
+        )
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// `17/Oct/2026:10:00:00 +0000`, always in UTC.
+fn format_clf_time(time: SystemTime) -> String {
+    let (year, month, day, hours, minutes, seconds) = utc_date_time(time);
+
+    format!(
+        "{day:02}/{}/{year}:{hours:02}:{minutes:02}:{seconds:02} +0000",
+        MONTHS[month as usize - 1]
+    )
+}
+
+/// `2026-10-17T10:00:00Z`.
+fn format_rfc3339(time: SystemTime) -> String {
+    let (year, month, day, hours, minutes, seconds) = utc_date_time(time);
+
+    format!("{year}-{month:02}-{day:02}T{hours:02}:{minutes:02}:{seconds:02}Z")
+}
+
+/// Civil date from the days since the unix epoch (Howard Hinnant's `civil_from_days`).
+fn utc_date_time(time: SystemTime) -> (i64, u32, u32, u64, u64, u64) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, day_secs) = ((secs / 86400) as i64, secs % 86400);
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        day_secs / 3600,
+        day_secs % 3600 / 60,
+        day_secs % 60,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{AccessLogEntry, AccessLogFormat, RequestLine};
+
+    fn entry() -> AccessLogEntry<'static> {
+        AccessLogEntry {
+            time: UNIX_EPOCH + Duration::from_secs(1_792_231_200),
+            client: Some("10.0.0.7:52000".parse().unwrap()),
+            request_line: Some(RequestLine {
+                method: "GET",
+                uri: "/users/42?full=\"1\"",
+                version: "HTTP/1.1",
+            }),
+            route: Some("/users/{id}"),
+            status: 200,
+            bytes: 17,
+            latency: Duration::from_micros(1500),
+            referer: None,
+            user_agent: Some("curl/8.0"),
+        }
+    }
+
+    #[test]
+    fn common_and_combined_formats() {
+        assert_eq!(
+            entry().format(AccessLogFormat::Common),
+            "10.0.0.7 - - [17/Oct/2026:10:00:00 +0000] \"GET /users/42?full=\\\"1\\\" HTTP/1.1\" 200 17"
+        );
+
+        let mut entry = entry();
+        entry.bytes = 0;
+
+        assert_eq!(
+            entry.format(AccessLogFormat::Combined),
+            "10.0.0.7 - - [17/Oct/2026:10:00:00 +0000] \"GET /users/42?full=\\\"1\\\" HTTP/1.1\" 200 - \"-\" \"curl/8.0\""
+        );
+    }
+
+    #[test]
+    fn json_format() {
+        let line: serde_json::Value =
+            serde_json::from_str(&entry().format(AccessLogFormat::Json)).unwrap();
+
+        assert_eq!(line["time"], "2026-10-17T10:00:00Z");
+        assert_eq!(line["client"], "10.0.0.7");
+        assert_eq!(line["route"], "/users/{id}");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["bytes"], 17);
+        assert_eq!(line["latency_ms"], 1.5);
+        assert_eq!(line["referer"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn rejected_request_without_request_line() {
+        let mut entry = entry();
+        entry.request_line = None;
+        entry.route = None;
+        entry.status = 431;
+
+        assert_eq!(
+            entry.format(AccessLogFormat::Common),
+            "10.0.0.7 - - [17/Oct/2026:10:00:00 +0000] \"-\" 431 17"
+        );
+
+        let line: serde_json::Value =
+            serde_json::from_str(&entry.format(AccessLogFormat::Json)).unwrap();
+
+        assert_eq!(line["method"], serde_json::Value::Null);
+        assert_eq!(line["status"], 431);
+    }
+}
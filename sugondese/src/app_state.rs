@@ -0,0 +1,61 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Values added with `WebApi::with_state`, one per type. Clones are cheap and share
+/// the values, so every worker thread and request sees the same state.
+#[derive(Clone, Default)]
+pub struct AppState(Arc<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>);
+
+impl AppState {
+    pub fn new() -> AppState {
+        AppState(Arc::new(HashMap::new()))
+    }
+
+    /// Adds the value, replacing the previous value of the same type.
+    pub fn insert<T>(&mut self, value: T)
+    where
+        T: Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.0).insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    pub fn get<T>(&self) -> Option<Arc<T>>
+    where
+        T: Send + Sync + 'static,
+    {
+        let value = Arc::clone(self.0.get(&TypeId::of::<T>())?);
+
+        value.downcast().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use super::AppState;
+
+    #[test]
+    fn values_are_shared_between_clones() {
+        let mut state = AppState::new();
+        state.insert(AtomicUsize::new(0));
+        state.insert("config".to_string());
+
+        let state_clone = state.clone();
+        state_clone
+            .get::<AtomicUsize>()
+            .unwrap()
+            .fetch_add(1, Ordering::SeqCst);
+
+        let counter = state.get::<AtomicUsize>().unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        assert!(Arc::ptr_eq(&counter, &state_clone.get().unwrap()));
+        assert_eq!(
+            state.get::<String>().as_deref(),
+            Some(&"config".to_string())
+        );
+        assert!(state.get::<u32>().is_none());
+    }
+}
@@ -0,0 +1,667 @@
+use std::cell::Cell;
+use std::io::{self, BufRead, ErrorKind, Read};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+#[cfg(feature = "tokio-tls")]
+use tokio::net::TcpStream;
+use tokio::task::{self, JoinError};
+use tokio::time;
+
+use crate::connection::{
+    buffer_chunked_body, keeps_alive, log_rejected, parse_error_response, ReadError, ServedRequest,
+};
+use crate::extract::ClientCertificate;
+use crate::headers::Headers;
+use crate::http_request::HttpRequest;
+use crate::http_response::{ChunkedBody, HttpResponse};
+use crate::middleware::Next;
+use crate::request_parser::{
+    body_framing, parse_request_head, streamed_body, write_chunk, write_chunked_head,
+    write_head_response, write_last_chunk, write_response, BodyFraming, ChunkDecoder,
+    MatchedHandler, ParseError,
+};
+use crate::shutdown::ServerHandle;
+use crate::web_api::{normalize_request, route_request, Endpoints, ServerConfig};
+
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const READ_BUFFER_SIZE: usize = 8192;
+
+/// Async counterpart of `serve_connection`: waiting for and reading requests doesn't
+/// hold a thread. Blocking handlers run on the runtime's blocking threads, async ones
+/// are awaited in a task of their own, so a panic is answered with 500 either way.
+/// `client` is `None` for Unix domain sockets.
+pub async fn serve_connection_async<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    client: Option<SocketAddr>,
+    endpoints: Arc<Endpoints>,
+    config: Arc<ServerConfig>,
+    handle: ServerHandle,
+) {
+    serve_stream(stream, client, None, endpoints, config, handle).await;
+}
+
+/// `serve_connection_async` over TLS, the handshake has to finish within the header
+/// timeout.
+#[cfg(feature = "tokio-tls")]
+pub async fn serve_tls_connection_async(
+    stream: TcpStream,
+    acceptor: tokio_rustls::TlsAcceptor,
+    endpoints: Arc<Endpoints>,
+    config: Arc<ServerConfig>,
+    handle: ServerHandle,
+) {
+    let client = stream.peer_addr().ok();
+
+    let stream = match time::timeout(config.header_timeout, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            log::debug!("TLS handshake with {client:?} failed: {e}");
+            return;
+        }
+        Err(_) => {
+            log::debug!("TLS handshake with {client:?} timed out");
+            return;
+        }
+    };
+    let client_certificate = crate::tls::client_certificate(stream.get_ref().1);
+
+    serve_stream(
+        stream,
+        client,
+        client_certificate,
+        endpoints,
+        config,
+        handle,
+    )
+    .await;
+}
+
+async fn serve_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    client: Option<SocketAddr>,
+    client_certificate: Option<ClientCertificate>,
+    endpoints: Arc<Endpoints>,
+    config: Arc<ServerConfig>,
+    handle: ServerHandle,
+) {
+    serve_requests(
+        &mut stream,
+        client,
+        client_certificate,
+        &endpoints,
+        &config,
+        &handle,
+    )
+    .await;
+
+    let _ = stream.shutdown().await;
+}
+
+async fn serve_requests<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    client: Option<SocketAddr>,
+    client_certificate: Option<ClientCertificate>,
+    endpoints: &Arc<Endpoints>,
+    config: &ServerConfig,
+    handle: &ServerHandle,
+) {
+    let mut buffer = Vec::new();
+    let mut served_requests = 0;
+
+    loop {
+        // Request on a just accepted connection is taken as in-flight even on shutdown.
+        let is_waiting = if served_requests == 0 {
+            wait_for_request(stream, &mut buffer, config.idle_timeout, None).await
+        } else {
+            let timeout = config.keep_alive_timeout;
+            wait_for_request(stream, &mut buffer, timeout, Some(handle)).await
+        };
+
+        if !is_waiting {
+            return;
+        }
+
+        let started_at = Instant::now();
+
+        let mut request = match read_request(stream, &mut buffer, config).await {
+            Ok(request) => request,
+            Err(ReadError {
+                error: ParseError::ConnectionClosed,
+                ..
+            }) => return,
+            Err(ReadError { error, head }) => {
+                log::debug!("failed to parse request from {client:?}: {error:?}");
+
+                let response = parse_error_response(&error);
+                let status = response.status.code();
+                let mut output = Vec::new();
+
+                if let Ok(bytes) = write_response(&mut output, response) {
+                    if send(stream, &output, config.write_timeout).await.is_ok() {
+                        log_rejected(config, client, head.as_deref(), started_at, status, bytes);
+                    }
+                }
+
+                return;
+            }
+        };
+
+        request.client_certificate = client_certificate.clone();
+        served_requests += 1;
+
+        let keep_alive = keeps_alive(&request, config, served_requests, handle);
+        let mut served = ServedRequest::new(&request, keep_alive);
+
+        let dispatched = match dispatch_async(request, endpoints).await {
+            Ok((mut response, route))
+                if served.needs_buffered_body() && response.chunked_body.is_some() =>
+            {
+                // The iterator may block or panic like the handler.
+                task::spawn_blocking(move || {
+                    buffer_chunked_body(&mut response);
+                    (response, route)
+                })
+                .await
+            }
+            dispatched => dispatched,
+        };
+
+        let (mut response, route) = match dispatched {
+            Ok(dispatched) => dispatched,
+            Err(error) => match error.try_into_panic() {
+                Ok(payload) => (served.report_panic(config, payload.as_ref()), None),
+                Err(error) => {
+                    log::warn!("handler serving {client:?} didn't finish: {error}");
+                    return;
+                }
+            },
+        };
+        let keep_alive = served.prepare_response(&mut response);
+        let status = response.status.code();
+
+        let chunks = if served.is_head {
+            None
+        } else {
+            streamed_body(&mut response)
+        };
+
+        let written = match chunks {
+            Some(chunks) => write_chunked_response(stream, response, chunks, config).await,
+            None => write_buffered_response(stream, response, served.is_head, config).await,
+        };
+
+        let bytes = match written {
+            Ok(bytes) => bytes,
+            Err(WriteError::Io(e)) => {
+                log::warn!("failed to write response to {client:?}: {e}");
+                return;
+            }
+            Err(WriteError::Body(error)) => {
+                // The response has already started, the connection is closed instead.
+                match error.try_into_panic() {
+                    Ok(payload) => {
+                        served.report_panic(config, payload.as_ref());
+                    }
+                    Err(error) => {
+                        log::warn!("chunked body for {client:?} didn't finish: {error}");
+                    }
+                }
+
+                return;
+            }
+        };
+
+        served.log(config, client, route.as_deref(), status, bytes);
+
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+/// Why a response couldn't be sent in full.
+enum WriteError {
+    Io(io::Error),
+    /// Iterator of a chunked body panicked or was cancelled.
+    Body(JoinError),
+}
+
+impl From<io::Error> for WriteError {
+    fn from(error: io::Error) -> WriteError {
+        WriteError::Io(error)
+    }
+}
+
+impl From<JoinError> for WriteError {
+    fn from(error: JoinError) -> WriteError {
+        WriteError::Body(error)
+    }
+}
+
+/// Returns the number of body bytes written.
+async fn write_buffered_response<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    response: HttpResponse,
+    is_head: bool,
+    config: &ServerConfig,
+) -> Result<usize, WriteError> {
+    let mut output = Vec::new();
+
+    let bytes = if is_head {
+        write_head_response(&mut output, response)?
+    } else {
+        write_response(&mut output, response)?
+    };
+
+    send(stream, &output, config.write_timeout).await?;
+
+    Ok(bytes)
+}
+
+/// Sends a chunked body as it's produced. The iterator may block, so every chunk is
+/// pulled on a blocking thread and written out before the next one is asked for.
+async fn write_chunked_response<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    response: HttpResponse,
+    mut chunks: ChunkedBody,
+    config: &ServerConfig,
+) -> Result<usize, WriteError> {
+    let mut frame = Vec::new();
+    let mut body_length = 0;
+
+    write_chunked_head(&mut frame, &response)?;
+    send(stream, &frame, config.write_timeout).await?;
+
+    loop {
+        let (chunk, rest) = task::spawn_blocking(move || (chunks.next(), chunks)).await?;
+        chunks = rest;
+
+        let Some(chunk) = chunk else {
+            break;
+        };
+
+        frame.clear();
+        write_chunk(&mut frame, &chunk)?;
+        send(stream, &frame, config.write_timeout).await?;
+        body_length += chunk.len();
+    }
+
+    frame.clear();
+    write_last_chunk(&mut frame)?;
+    send(stream, &frame, config.write_timeout).await?;
+
+    Ok(body_length)
+}
+
+/// Writes and flushes `data`, a client which doesn't take it within `timeout` is
+/// given up on.
+async fn send<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    data: &[u8],
+    timeout: Duration,
+) -> io::Result<()> {
+    let sent = async {
+        stream.write_all(data).await?;
+        stream.flush().await
+    };
+
+    time::timeout(timeout, sent)
+        .await
+        .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()))
+}
+
+/// Runs the middleware chain and the handler, returns the response together with the
+/// matched route template. Middlewares are blocking, so a chain runs on a blocking
+/// thread as a whole and async handlers called by it are waited for there. Like in
+/// `handle_request`, the path is normalized before the middlewares see it.
+async fn dispatch_async(
+    request: HttpRequest,
+    endpoints: &Arc<Endpoints>,
+) -> Result<(HttpResponse, Option<String>), JoinError> {
+    let request = match normalize_request(request) {
+        Ok(request) => request,
+        Err(response) => return Ok((response, None)),
+    };
+
+    if !endpoints.middlewares.is_empty() {
+        let endpoints = Arc::clone(endpoints);
+
+        return task::spawn_blocking(move || {
+            let matched_route = Cell::new(None);
+            let response = Next::new(&endpoints, &matched_route).run(request);
+
+            (response, matched_route.take())
+        })
+        .await;
+    }
+
+    let MatchedHandler {
+        handler,
+        route,
+        request,
+    } = match route_request(request, endpoints) {
+        Ok(matched) => matched,
+        Err(response) => return Ok((response, None)),
+    };
+
+    let response = if handler.is_async() {
+        task::spawn(handler.handle_async(request)).await
+    } else {
+        task::spawn_blocking(move || handler.handle(request)).await
+    };
+
+    response.map(|response| (response, Some(route)))
+}
+
+/// Waits until the client starts sending a request, `false` if it closed the
+/// connection, stayed idle for `timeout` or the server is shutting down.
+async fn wait_for_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    timeout: Duration,
+    handle: Option<&ServerHandle>,
+) -> bool {
+    let started_at = Instant::now();
+
+    loop {
+        if !buffer.is_empty() {
+            return true;
+        }
+
+        if handle.is_some_and(|handle| handle.is_shutting_down()) {
+            return false;
+        }
+
+        let remaining = timeout.saturating_sub(started_at.elapsed());
+
+        if remaining.is_zero() {
+            return false;
+        }
+
+        match read_more(stream, buffer, remaining.min(SHUTDOWN_POLL_INTERVAL)).await {
+            Ok(read) => return read > 0,
+            Err(e) if e.kind() == ErrorKind::TimedOut => {}
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Parses the next request from the buffer, reading from the stream until the request
+/// is complete. The parsed bytes are removed, pipelined requests stay in the buffer.
+/// The headers and then the body have to arrive before their timeouts expire.
+async fn read_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    config: &ServerConfig,
+) -> Result<HttpRequest, ReadError> {
+    let (mut request, head_length) = read_head(stream, buffer, config)
+        .await
+        .map_err(|error| ReadError { error, head: None })?;
+
+    let (headers, trailers) = (&request.headers, &mut request.trailers);
+
+    match read_body(stream, buffer, head_length, headers, trailers, config).await {
+        Ok(body) => {
+            request.body = body;
+            Ok(request)
+        }
+        Err(error) => Err(ReadError {
+            error,
+            head: Some(Box::new(request)),
+        }),
+    }
+}
+
+/// Parses the request line and headers, returns them with the number of bytes they
+/// took. The head is parsed once its end has arrived, or once the buffer holds more
+/// than the limits allow so the parser reports which one is exceeded.
+async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    config: &ServerConfig,
+) -> Result<(HttpRequest, usize), ParseError> {
+    let deadline = Instant::now() + config.header_timeout;
+    let limits = &config.limits;
+    // The request line, the header lines and the empty line ending the head.
+    let max_head = limits.max_request_line + limits.max_header_size + 2;
+    let mut searched: usize = 0;
+
+    loop {
+        // Clients may send empty lines between pipelined requests.
+        let empty_lines = buffer
+            .iter()
+            .take_while(|byte| matches!(byte, b'\r' | b'\n'))
+            .count();
+        buffer.drain(..empty_lines);
+        searched = searched.saturating_sub(empty_lines);
+
+        let head_end = find_head_end(buffer, searched);
+
+        if head_end.is_some() || buffer.len() > max_head {
+            let mut reader = BufferReader::new(buffer);
+            let parsed = parse_request_head(&mut reader, limits);
+
+            if !reader.reached_end {
+                return parsed.map(|request| (request, reader.consumed));
+            }
+
+            // Over the limits and still no complete head.
+            if buffer.len() > max_head {
+                return Err(ParseError::HeadersTooLarge);
+            }
+        }
+
+        // The end of the head may start in the bytes already searched.
+        searched = buffer.len().saturating_sub(2);
+
+        let incomplete = match buffer.is_empty() {
+            true => ParseError::ConnectionClosed,
+            false => ParseError::BadRequest,
+        };
+        read_more_before(stream, buffer, deadline, incomplete).await?;
+    }
+}
+
+/// Finds the empty line which ends the head, searching from `from`. Returns where the
+/// body starts.
+fn find_head_end(buffer: &[u8], from: usize) -> Option<usize> {
+    buffer
+        .get(from..)?
+        .windows(2)
+        .enumerate()
+        .find_map(|(at, window)| match window {
+            b"\n\n" => Some(from + at + 2),
+            b"\n\r" if buffer.get(from + at + 2) == Some(&b'\n') => Some(from + at + 3),
+            _ => None,
+        })
+}
+
+/// Reads the body which follows the head, bytes are only appended as they arrive and the
+/// request is removed from the buffer as it's read. The body gets a deadline of its own
+/// once the headers have arrived.
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    head_length: usize,
+    headers: &Headers,
+    trailers: &mut Headers,
+    config: &ServerConfig,
+) -> Result<Option<Vec<u8>>, ParseError> {
+    let deadline = Instant::now() + config.body_timeout;
+    let framing = body_framing(headers, &config.limits)?;
+
+    buffer.drain(..head_length);
+
+    let body = match framing {
+        BodyFraming::Length(body_length) => {
+            buffer.reserve(body_length.saturating_sub(buffer.len()));
+
+            while buffer.len() < body_length {
+                read_more_before(stream, buffer, deadline, ParseError::BadRequest).await?;
+            }
+
+            // Pipelined requests after the body stay in the buffer.
+            let rest = buffer.split_off(body_length);
+            mem::replace(buffer, rest)
+        }
+        BodyFraming::Chunked => {
+            let mut decoder = ChunkDecoder::default();
+
+            loop {
+                let mut reader = BufferReader::new(buffer);
+                let decoded = decoder.decode(&mut reader, &config.limits);
+
+                // The decoder is left as it was by a step cut short, the step is tried
+                // again once more bytes arrive.
+                if reader.reached_end {
+                    let incomplete = decoded.err().unwrap_or(ParseError::BadRequest);
+                    read_more_before(stream, buffer, deadline, incomplete).await?;
+                    continue;
+                }
+
+                let consumed = reader.consumed;
+                buffer.drain(..consumed);
+
+                if let Some(fields) = decoded? {
+                    *trailers = fields;
+                    break;
+                }
+            }
+
+            decoder.body
+        }
+    };
+
+    Ok(Some(body).filter(|body| !body.is_empty()))
+}
+
+/// Waits for more of the request until `deadline`, `incomplete` is returned if the
+/// client closes the connection first.
+async fn read_more_before<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    deadline: Instant,
+    incomplete: ParseError,
+) -> Result<(), ParseError> {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+
+    match read_more(stream, buffer, remaining).await {
+        Ok(read) if read > 0 => Ok(()),
+        Err(e) if e.kind() == ErrorKind::TimedOut => Err(ParseError::Timeout),
+        _ => Err(incomplete),
+    }
+}
+
+async fn read_more<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+    timeout: Duration,
+) -> io::Result<usize> {
+    // The buffer only grows once it's full, a body may have reserved its length.
+    if buffer.len() == buffer.capacity() {
+        buffer.reserve(READ_BUFFER_SIZE);
+    }
+
+    time::timeout(timeout, stream.read_buf(buffer))
+        .await
+        .unwrap_or_else(|_| Err(ErrorKind::TimedOut.into()))
+}
+
+/// Reader over the bytes received so far, remembers whether the parser ran out of them.
+struct BufferReader<'a> {
+    data: &'a [u8],
+    consumed: usize,
+    reached_end: bool,
+}
+
+impl BufferReader<'_> {
+    fn new(data: &[u8]) -> BufferReader<'_> {
+        BufferReader {
+            data,
+            consumed: 0,
+            reached_end: false,
+        }
+    }
+}
+
+impl Read for BufferReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+
+        Ok(read)
+    }
+}
+
+impl BufRead for BufferReader<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let rest = &self.data[self.consumed..];
+
+        if rest.is_empty() {
+            self.reached_end = true;
+        }
+
+        Ok(rest)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consumed += amt;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::request_parser::{parse_request, ParseError, RequestLimits};
+
+    use super::{find_head_end, BufferReader};
+
+    #[test]
+    fn find_head_end_resumes_within_a_line_end() {
+        let head = b"GET / HTTP/1.1\r\nHost: a\r\n\r\nbody";
+
+        assert_eq!(find_head_end(head, 0), Some(27));
+        assert_eq!(find_head_end(&head[..26], 0), None);
+        assert_eq!(find_head_end(head, 24), Some(27));
+        assert_eq!(find_head_end(b"GET / HTTP/1.0\n\n", 12), Some(16));
+        assert_eq!(find_head_end(b"GET", 5), None);
+    }
+
+    #[test]
+    fn buffer_reader_tells_incomplete_requests_apart() {
+        let data = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel";
+        let mut reader = BufferReader::new(data);
+
+        assert_eq!(
+            parse_request(&mut reader, &RequestLimits::default()).unwrap_err(),
+            ParseError::BadRequest
+        );
+        assert!(reader.reached_end);
+
+        let data = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b";
+        let mut reader = BufferReader::new(data);
+
+        assert_eq!(
+            parse_request(&mut reader, &RequestLimits::default())
+                .unwrap()
+                .body
+                .as_deref(),
+            Some("hello".as_bytes())
+        );
+        assert!(!reader.reached_end);
+        assert_eq!(&data[reader.consumed..], b"GET /b");
+
+        let mut reader = BufferReader::new(b"BREW /pot HTTP/1.1\r\n");
+
+        assert_eq!(
+            parse_request(&mut reader, &RequestLimits::default()).unwrap_err(),
+            ParseError::NotImplemented
+        );
+        assert!(!reader.reached_end);
+    }
+}
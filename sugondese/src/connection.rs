@@ -0,0 +1,537 @@
+use std::cell::Cell;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::access_log::{AccessLogEntry, RequestLine, ACCESS_LOG_TARGET};
+use crate::extract::ClientCertificate;
+use crate::http_request::{is_http_1_0, HttpRequest};
+use crate::http_response::{HttpResponse, HttpStatus};
+use crate::method_verb::HttpMethod;
+use crate::request_parser::{
+    parse_request_head, read_request_body, write_head_response, write_response, ParseError,
+};
+use crate::shutdown::ServerHandle;
+use crate::web_api::{handle_request, Endpoints, HandlerPanic, ServerConfig};
+
+const CONNECTION_HEADER: &str = "Connection";
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const REFERER_HEADER: &str = "Referer";
+const USER_AGENT_HEADER: &str = "User-Agent";
+
+/// Serves sequential requests from one client until either side asks to close the
+/// connection, the client stays idle for too long or the per-connection request
+/// limit is reached. On shutdown the request being served is finished and idle
+/// connections are closed.
+pub fn serve_connection<S: Transport>(
+    stream: S,
+    endpoints: &Endpoints,
+    config: &ServerConfig,
+    handle: &ServerHandle,
+) {
+    // A client which stops reading the response can't hold the worker either.
+    if let Err(e) = stream.set_write_timeout(Some(config.write_timeout)) {
+        log::debug!("failed to set write timeout: {e}");
+    }
+
+    let mut reader = BufReader::new(DeadlineStream {
+        stream,
+        deadline: None,
+    });
+
+    serve_requests(&mut reader, endpoints, config, handle);
+    reader.get_mut().close();
+}
+
+fn serve_requests<S: Transport>(
+    reader: &mut BufReader<DeadlineStream<S>>,
+    endpoints: &Endpoints,
+    config: &ServerConfig,
+    handle: &ServerHandle,
+) {
+    let client = reader.get_ref().peer_addr();
+    let mut served_requests = 0;
+
+    loop {
+        // Request on a just accepted connection is taken as in-flight even on shutdown.
+        let is_waiting = if served_requests == 0 {
+            wait_for_request(reader, config.idle_timeout, None)
+        } else {
+            wait_for_request(reader, config.keep_alive_timeout, Some(handle))
+        };
+
+        if !is_waiting {
+            return;
+        }
+
+        let started_at = Instant::now();
+
+        let mut request = match read_request(reader, config) {
+            Ok(request) => request,
+            Err(ReadError {
+                error: ParseError::ConnectionClosed,
+                ..
+            }) => return,
+            Err(ReadError { error, head }) => {
+                log::debug!("failed to parse request from {client:?}: {error:?}");
+
+                let response = parse_error_response(&error);
+                let status = response.status.code();
+
+                if let Ok(bytes) = write_response(reader.get_mut(), response) {
+                    log_rejected(config, client, head.as_deref(), started_at, status, bytes);
+                }
+
+                return;
+            }
+        };
+
+        served_requests += 1;
+        request.client_certificate = reader.get_ref().client_certificate();
+
+        let keep_alive = keeps_alive(&request, config, served_requests, handle);
+        let mut served = ServedRequest::new(&request, keep_alive);
+
+        let matched_route = Cell::new(None);
+        let dispatched = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut response = handle_request(request, endpoints, &matched_route);
+
+            if served.needs_buffered_body() {
+                buffer_chunked_body(&mut response);
+            }
+
+            response
+        }));
+
+        let mut response = match dispatched {
+            Ok(response) => response,
+            Err(payload) => served.report_panic(config, payload.as_ref()),
+        };
+        let keep_alive = served.prepare_response(&mut response);
+        let status = response.status.code();
+
+        // A chunked body is produced while it's written, its iterator may panic too.
+        let written = panic::catch_unwind(AssertUnwindSafe(|| {
+            if served.is_head {
+                write_head_response(reader.get_mut(), response)
+            } else {
+                write_response(reader.get_mut(), response)
+            }
+        }));
+
+        let write_result = match written {
+            Ok(write_result) => write_result,
+            Err(payload) => {
+                // The response has already started, the connection is closed instead.
+                served.report_panic(config, payload.as_ref());
+                return;
+            }
+        };
+
+        let bytes = match write_result {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                log::warn!("failed to write response to {client:?}: {e}");
+                return;
+            }
+        };
+
+        served.log(
+            config,
+            client,
+            matched_route.take().as_deref(),
+            status,
+            bytes,
+        );
+
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+/// Request which couldn't be read.
+pub(crate) struct ReadError {
+    pub error: ParseError,
+    /// Request line and headers, if they were parsed before the body failed.
+    pub head: Option<Box<HttpRequest>>,
+}
+
+/// Reads the request the client started sending, the headers and then the body have to
+/// arrive before their timeouts expire.
+fn read_request<S: Transport>(
+    reader: &mut BufReader<DeadlineStream<S>>,
+    config: &ServerConfig,
+) -> Result<HttpRequest, ReadError> {
+    reader.get_mut().deadline = Some(Instant::now() + config.header_timeout);
+
+    let request = match parse_request_head(reader, &config.limits) {
+        Ok(mut request) => {
+            reader.get_mut().deadline = Some(Instant::now() + config.body_timeout);
+
+            let (headers, trailers) = (&request.headers, &mut request.trailers);
+
+            match read_request_body(reader, headers, trailers, &config.limits) {
+                Ok(body) => {
+                    request.body = body;
+                    Ok(request)
+                }
+                Err(error) => Err(ReadError {
+                    error,
+                    head: Some(Box::new(request)),
+                }),
+            }
+        }
+        Err(error) => Err(ReadError { error, head: None }),
+    };
+
+    reader.get_mut().deadline = None;
+    request
+}
+
+/// Waits until the client starts sending a request, `false` if it closed the
+/// connection, stayed idle for `timeout` or the server is shutting down. Polls in short
+/// intervals, so an idle connection doesn't hold the shutdown up.
+fn wait_for_request<S: Transport>(
+    reader: &mut BufReader<S>,
+    timeout: Duration,
+    handle: Option<&ServerHandle>,
+) -> bool {
+    let started_at = Instant::now();
+
+    loop {
+        if !reader.buffer().is_empty() {
+            return true;
+        }
+
+        if handle.is_some_and(|handle| handle.is_shutting_down()) {
+            return false;
+        }
+
+        let remaining = timeout.saturating_sub(started_at.elapsed());
+
+        if remaining.is_zero()
+            || reader
+                .get_ref()
+                .set_read_timeout(Some(remaining.min(SHUTDOWN_POLL_INTERVAL)))
+                .is_err()
+        {
+            return false;
+        }
+
+        match reader.fill_buf() {
+            Ok(buffer) => return !buffer.is_empty(),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted
+                ) => {}
+            Err(_) => return false,
+        }
+    }
+}
+
+/// Stream a connection is served over: plain TCP, a TLS session on top of it or a Unix
+/// domain socket.
+pub(crate) trait Transport: Read + Write {
+    /// Client address, `None` for Unix domain sockets.
+    fn peer_addr(&self) -> Option<SocketAddr>;
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Certificate the client presented during the TLS handshake.
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        None
+    }
+
+    /// Called before the connection is dropped.
+    fn close(&mut self) {}
+}
+
+/// Stream failing reads with a timeout once the deadline has passed, so a client
+/// sending its request slowly can't hold the worker for longer than the timeouts.
+struct DeadlineStream<S> {
+    stream: S,
+    deadline: Option<Instant>,
+}
+
+impl<S: Transport> Read for DeadlineStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if remaining.is_zero() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+
+            self.stream.set_read_timeout(Some(remaining))?;
+        }
+
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Transport> Write for DeadlineStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: Transport> Transport for DeadlineStream<S> {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        self.stream.client_certificate()
+    }
+
+    fn close(&mut self) {
+        self.stream.close()
+    }
+}
+
+impl Transport for TcpStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        TcpStream::peer_addr(self).ok()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+}
+
+/// Whether the connection may serve another request after this one.
+pub(crate) fn keeps_alive(
+    request: &HttpRequest,
+    config: &ServerConfig,
+    served_requests: usize,
+    handle: &ServerHandle,
+) -> bool {
+    config.keep_alive
+        && request.keep_alive()
+        && served_requests < config.max_requests_per_connection
+        && !handle.is_shutting_down()
+}
+
+/// Collects a chunked body into a buffered one. The iterator may panic, so this runs
+/// where a panic is still answered with 500.
+pub(crate) fn buffer_chunked_body(response: &mut HttpResponse) {
+    if let Some(chunks) = response.chunked_body.take() {
+        response.body = Some(chunks.flatten().collect());
+    }
+}
+
+/// Request line and headers kept after the request is dispatched, to finish the
+/// response and write the access log line.
+pub(crate) struct ServedRequest {
+    started_at: Instant,
+    received_at: SystemTime,
+    method: HttpMethod,
+    uri: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    keep_alive: bool,
+    pub is_head: bool,
+}
+
+impl ServedRequest {
+    pub fn new(request: &HttpRequest, keep_alive: bool) -> ServedRequest {
+        ServedRequest {
+            started_at: Instant::now(),
+            received_at: SystemTime::now(),
+            method: request.method,
+            uri: request.uri.clone(),
+            version: request.version.clone(),
+            referer: request.headers.get(REFERER_HEADER).map(str::to_string),
+            user_agent: request.headers.get(USER_AGENT_HEADER).map(str::to_string),
+            keep_alive,
+            is_head: request.method == HttpMethod::Head,
+        }
+    }
+
+    /// HTTP/1.0 clients don't understand chunked encoding, a chunked body is collected
+    /// with `buffer_chunked_body` before the response is sent to them.
+    pub fn needs_buffered_body(&self) -> bool {
+        is_http_1_0(&self.version)
+    }
+
+    /// Sets the `Connection` header for the client's version, returns whether the
+    /// connection stays open. Invalid custom statuses are replaced with 500.
+    pub fn prepare_response(&mut self, response: &mut HttpResponse) -> bool {
+        let is_http_1_0 = self.needs_buffered_body();
+
+        if !response.status.is_valid() {
+            log::error!(
+                "handler for {} {} returned invalid status {:?}, answering with 500",
+                self.method.as_str(),
+                self.uri,
+                response.status
+            );
+
+            *response = HttpResponse::new(HttpStatus::InternalServerError, None);
+        }
+
+        if response
+            .headers
+            .get(CONNECTION_HEADER)
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"))
+        {
+            self.keep_alive = false;
+        }
+
+        if !self.keep_alive {
+            response.headers.insert(CONNECTION_HEADER, "close");
+        } else if is_http_1_0 {
+            response.headers.insert(CONNECTION_HEADER, "keep-alive");
+        }
+
+        self.keep_alive
+    }
+
+    /// Logs the panic, passes it to the panic hook and returns the response sent instead.
+    pub fn report_panic(
+        &self,
+        config: &ServerConfig,
+        payload: &(dyn std::any::Any + Send),
+    ) -> HttpResponse {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+
+        log::error!(
+            "handler for {} {} panicked: {message}",
+            self.method.as_str(),
+            self.uri
+        );
+
+        if let Some(hook) = &config.panic_hook {
+            hook(&HandlerPanic {
+                method: self.method,
+                uri: self.uri.clone(),
+                message,
+            });
+        }
+
+        HttpResponse::new(HttpStatus::InternalServerError, None)
+    }
+
+    pub fn log(
+        &self,
+        config: &ServerConfig,
+        client: Option<SocketAddr>,
+        route: Option<&str>,
+        status: u16,
+        bytes: usize,
+    ) {
+        if !log::log_enabled!(target: ACCESS_LOG_TARGET, log::Level::Info) {
+            return;
+        }
+
+        let entry = AccessLogEntry {
+            time: self.received_at,
+            client,
+            request_line: Some(RequestLine {
+                method: self.method.as_str(),
+                uri: &self.uri,
+                version: &self.version,
+            }),
+            route,
+            status,
+            bytes,
+            latency: self.started_at.elapsed(),
+            referer: self.referer.as_deref(),
+            user_agent: self.user_agent.as_deref(),
+        };
+
+        log::info!(target: ACCESS_LOG_TARGET, "{}", entry.format(config.access_log_format));
+    }
+}
+
+/// Access log line of a request answered without reaching a handler: it couldn't be
+/// read, or every worker was busy. The request line is `-` unless `head` has it.
+pub(crate) fn log_rejected(
+    config: &ServerConfig,
+    client: Option<SocketAddr>,
+    head: Option<&HttpRequest>,
+    started_at: Instant,
+    status: u16,
+    bytes: usize,
+) {
+    if !log::log_enabled!(target: ACCESS_LOG_TARGET, log::Level::Info) {
+        return;
+    }
+
+    let latency = started_at.elapsed();
+    let entry = AccessLogEntry {
+        time: SystemTime::now() - latency,
+        client,
+        request_line: head.map(|request| RequestLine {
+            method: request.method.as_str(),
+            uri: &request.uri,
+            version: &request.version,
+        }),
+        route: None,
+        status,
+        bytes,
+        latency,
+        referer: head.and_then(|request| request.headers.get(REFERER_HEADER)),
+        user_agent: head.and_then(|request| request.headers.get(USER_AGENT_HEADER)),
+    };
+
+    log::info!(target: ACCESS_LOG_TARGET, "{}", entry.format(config.access_log_format));
+}
+
+/// Response to a request which couldn't be parsed, the connection is closed after it.
+pub(crate) fn parse_error_response(error: &ParseError) -> HttpResponse {
+    let status = match error {
+        ParseError::NotImplemented => HttpStatus::NotImplemented,
+        ParseError::UriTooLong => HttpStatus::UriTooLong,
+        ParseError::HeadersTooLarge => HttpStatus::RequestHeaderFieldsTooLarge,
+        ParseError::PayloadTooLarge => HttpStatus::ContentTooLarge,
+        ParseError::Timeout => HttpStatus::RequestTimeout,
+        ParseError::VersionNotSupported => HttpStatus::HttpVersionNotSupported,
+        ParseError::ConnectionClosed | ParseError::BadRequest => HttpStatus::BadRequest,
+    };
+
+    HttpResponse::new(status, None).header(CONNECTION_HEADER, "close")
+}
@@ -0,0 +1,117 @@
+use std::any::type_name;
+use std::fmt::{Display, Formatter};
+use std::ops::Deref;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+
+use crate::app_state::AppState;
+use crate::params_deserializer::from_params;
+use crate::uri_params::{Query, Route};
+
+/// Route params deserialized into `T`, e.g. `Path<UserPath>` for `/users/{id}` where
+/// `UserPath` has an `id: u64` field.
+#[derive(Debug)]
+pub struct Path<T>(pub T);
+
+/// Query params deserialized into `T`. Optional params should be `Option` fields.
+#[derive(Debug)]
+pub struct QueryOf<T>(pub T);
+
+/// Value added with `WebApi::with_state`, shared by all requests.
+#[derive(Debug)]
+pub struct State<T>(pub Arc<T>);
+
+/// DER encoded certificate the client presented on a TLS connection, see
+/// `WebApi::tls_client_auth`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientCertificate(pub Vec<u8>);
+
+/// Raw request body, e.g. an uploaded file or a protobuf message. Empty if the request
+/// has no body. Also a `Response` body sent as is, see `Response::bytes`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bytes(pub Vec<u8>);
+
+/// Params couldn't be converted into the requested type, answered with 400.
+#[derive(Debug)]
+pub struct ExtractError(String);
+
+impl<T> Path<T>
+where
+    T: DeserializeOwned,
+{
+    pub fn from_route(route: &Route) -> Result<Path<T>, ExtractError> {
+        let params = route.0.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+
+        from_params(params)
+            .map(Path)
+            .map_err(|e| ExtractError(format!("Invalid route params: {e}")))
+    }
+}
+
+impl<T> QueryOf<T>
+where
+    T: DeserializeOwned,
+{
+    pub fn from_query(query: &Query) -> Result<QueryOf<T>, ExtractError> {
+        let params = query.0.iter().map(|(k, v)| (k.as_str(), v.as_str()));
+
+        from_params(params)
+            .map(QueryOf)
+            .map_err(|e| ExtractError(format!("Invalid query params: {e}")))
+    }
+}
+
+impl<T> State<T>
+where
+    T: Send + Sync + 'static,
+{
+    /// Fails if no value of type `T` was added, answered with 500.
+    pub fn from_app_state(state: &AppState) -> Result<State<T>, ExtractError> {
+        state.get().map(State).ok_or_else(|| {
+            ExtractError(format!(
+                "State of type {} isn't registered, add it with WebApi::with_state",
+                type_name::<T>()
+            ))
+        })
+    }
+}
+
+impl ClientCertificate {
+    /// Fails if the client didn't present a certificate, answered with 401.
+    pub fn from_client_certificate(
+        certificate: &Option<ClientCertificate>,
+    ) -> Result<ClientCertificate, ExtractError> {
+        certificate
+            .clone()
+            .ok_or_else(|| ExtractError("Client certificate is required".to_string()))
+    }
+}
+
+impl Bytes {
+    pub fn from_body(body: Option<Vec<u8>>) -> Bytes {
+        Bytes(body.unwrap_or_default())
+    }
+}
+
+impl<T> Deref for State<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Display for ExtractError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
@@ -0,0 +1,188 @@
+use std::future::Future;
+use std::pin::Pin;
+#[cfg(feature = "tokio")]
+use std::{panic, sync::mpsc};
+#[cfg(not(feature = "tokio"))]
+use std::{
+    pin::pin,
+    sync::Arc,
+    task::{Context, Poll, Wake, Waker},
+    thread::{self, Thread},
+};
+
+use crate::app_state::AppState;
+use crate::extract::ClientCertificate;
+use crate::headers::Headers;
+use crate::http_response::{HttpResponse, IntoHttpResponse};
+use crate::uri_params::{Query, Route};
+
+pub type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Everything a handler gets from the request it serves.
+pub struct HandlerRequest {
+    pub route: Route,
+    pub query: Query,
+    pub headers: Headers,
+    pub state: AppState,
+    pub body: Option<Vec<u8>>,
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+/// Request handler registered for a route. Implemented for the wrappers generated by
+/// `#[http_handler]` and for closures taking a `HandlerRequest`, e.g.
+/// `|request: HandlerRequest| Response::ok(request.query.get("name").map(str::to_string))`.
+pub trait Handler {
+    fn handle(&self, request: HandlerRequest) -> HttpResponse;
+
+    /// Async handlers are awaited by the async server instead of being called on a
+    /// blocking thread.
+    fn is_async(&self) -> bool {
+        false
+    }
+
+    /// Future answering the request, only called when `is_async` is `true`.
+    fn handle_async(&self, request: HandlerRequest) -> BoxFuture<HttpResponse> {
+        let response = self.handle(request);
+
+        Box::pin(async move { response })
+    }
+}
+
+impl<F, R> Handler for F
+where
+    F: Fn(HandlerRequest) -> R,
+    R: IntoHttpResponse,
+{
+    fn handle(&self, request: HandlerRequest) -> HttpResponse {
+        self(request).into_http_response()
+    }
+}
+
+/// Handler returning a future, used for `async fn` handlers and for closures like
+/// `AsyncHandler(|request: HandlerRequest| async move { ... })`. The blocking server
+/// (and a middleware chain on the async one) waits for the future on the worker thread.
+pub struct AsyncHandler<F>(pub F);
+
+impl<F, Fut, R> Handler for AsyncHandler<F>
+where
+    F: Fn(HandlerRequest) -> Fut,
+    Fut: Future<Output = R> + Send + 'static,
+    R: IntoHttpResponse,
+{
+    fn handle(&self, request: HandlerRequest) -> HttpResponse {
+        block_on(self.handle_async(request))
+    }
+
+    fn is_async(&self) -> bool {
+        true
+    }
+
+    fn handle_async(&self, request: HandlerRequest) -> BoxFuture<HttpResponse> {
+        let future = (self.0)(request);
+
+        Box::pin(async move { future.await.into_http_response() })
+    }
+}
+
+/// Waits for the future on the current thread, so it can use tokio timers and io on
+/// worker threads of the blocking server too. Called from a blocking thread of a tokio
+/// runtime, the future runs as a task of that runtime: `Handle::block_on` can't drive
+/// the timers and io of a current-thread runtime. Otherwise it runs on a runtime of the
+/// thread.
+#[cfg(feature = "tokio")]
+fn block_on<F>(future: F) -> F::Output
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    thread_local! {
+        static RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to start tokio runtime");
+    }
+
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return RUNTIME.with(|runtime| runtime.block_on(future));
+    };
+
+    let (sender, receiver) = mpsc::sync_channel(1);
+    let task = runtime.spawn(future);
+
+    runtime.spawn(async move {
+        let _ = sender.send(task.await);
+    });
+
+    match receiver.recv() {
+        Ok(Ok(output)) => output,
+        Ok(Err(error)) => match error.try_into_panic() {
+            Ok(payload) => panic::resume_unwind(payload),
+            Err(error) => panic!("async handler didn't finish: {error}"),
+        },
+        Err(_) => panic!("async handler didn't finish: the runtime shut down"),
+    }
+}
+
+/// Runs the future to completion on the current thread, parking it while the future
+/// is pending.
+#[cfg(not(feature = "tokio"))]
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut context = Context::from_waker(&waker);
+
+    loop {
+        match future.as_mut().poll(&mut context) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+#[cfg(not(feature = "tokio"))]
+struct ThreadWaker(Thread);
+
+#[cfg(not(feature = "tokio"))]
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::app_state::AppState;
+    use crate::headers::Headers;
+    use crate::http_response::HttpResponse;
+    use crate::uri_params::{Query, Route};
+
+    use super::{AsyncHandler, Handler, HandlerRequest};
+
+    fn request() -> HandlerRequest {
+        HandlerRequest {
+            route: Route(HashMap::new()),
+            query: Query(vec![("name".to_string(), "ligma".to_string())]),
+            headers: Headers::new(),
+            state: AppState::default(),
+            body: None,
+            client_certificate: None,
+        }
+    }
+
+    #[test]
+    fn async_handler_is_driven_on_blocking_thread() {
+        let handler = AsyncHandler(|request: HandlerRequest| async move {
+            std::future::ready(()).await;
+            HttpResponse::ok(request.query.get("name").map(str::to_string))
+        });
+
+        assert!(handler.is_async());
+        assert_eq!(
+            handler.handle(request()).body.as_deref(),
+            Some("ligma".as_bytes())
+        );
+        assert!(!(|_: HandlerRequest| HttpResponse::ok(None)).is_async());
+    }
+}
@@ -0,0 +1,88 @@
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    pub fn new() -> Headers {
+        Headers(Vec::new())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Adds a header, keeping any values already stored under the same name.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.0.push((name.to_string(), value.to_string()));
+    }
+
+    /// Sets a header, replacing all values already stored under the same name.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.0.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Headers;
+
+    #[test]
+    fn get_is_case_insensitive() {
+        let mut headers = Headers::new();
+        headers.append("X-Request-Id", "42");
+
+        assert_eq!(headers.get("x-request-id"), Some("42"));
+        assert_eq!(headers.get("X-REQUEST-ID"), Some("42"));
+        assert_eq!(headers.get("accept"), None);
+    }
+
+    #[test]
+    fn insert_replaces_all_values() {
+        let mut headers = Headers::new();
+        headers.append("Accept", "text/html");
+        headers.append("accept", "application/json");
+
+        assert_eq!(
+            headers.get_all("ACCEPT"),
+            vec!["text/html", "application/json"]
+        );
+
+        headers.insert("Accept", "*/*");
+
+        assert_eq!(headers.get_all("accept"), vec!["*/*"]);
+        assert_eq!(headers.len(), 1);
+    }
+}
@@ -1,7 +1,8 @@
-use crate::http_response::HttpResponse;
-use crate::uri_params::{Query, Route};
+use std::sync::Arc;
+
+use crate::handler::Handler;
 
 pub struct HttpHandlerInfo {
-    pub handler: fn(Route, Query, Option<String>) -> HttpResponse,
+    pub handler: Arc<dyn Handler + Send + Sync>,
     pub route: String,
 }
@@ -1,8 +1,51 @@
+use crate::extract::ClientCertificate;
+use crate::headers::Headers;
 use crate::method_verb::HttpMethod;
 
+const CONNECTION_HEADER: &str = "Connection";
+const HTTP_1_0: &str = "HTTP/1.0";
+const HTTP_1_1: &str = "HTTP/1.1";
+
 #[derive(Debug)]
 pub struct HttpRequest {
     pub method: HttpMethod,
     pub uri: String,
-    pub body: Option<String>,
+    pub version: String,
+    pub headers: Headers,
+    pub body: Option<Vec<u8>>,
+    /// Fields sent after a chunked body. They are kept apart from `headers`, a client
+    /// could otherwise add e.g. `Authorization` after the body (RFC 9110 section 6.5.1).
+    pub trailers: Headers,
+    /// Certificate the client presented, only on TLS connections with client auth.
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+impl HttpRequest {
+    /// HTTP/1.1 connections are persistent unless the client asks to close them,
+    /// older versions have to opt in with `Connection: keep-alive`.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.headers.get(CONNECTION_HEADER).unwrap_or("");
+        let has_option = |option: &str| {
+            connection
+                .split(',')
+                .any(|value| value.trim().eq_ignore_ascii_case(option))
+        };
+
+        if has_option("close") {
+            return false;
+        }
+
+        has_option("keep-alive") || !is_http_1_0(&self.version)
+    }
+}
+
+/// Whether the client speaks HTTP/1.0, and so doesn't know chunked encoding and
+/// persistent connections by default. The parser only lets HTTP/1.x requests through.
+pub(crate) fn is_http_1_0(version: &str) -> bool {
+    match version {
+        HTTP_1_0 => true,
+        HTTP_1_1 => false,
+        // Later minor versions are answered like HTTP/1.1 (RFC 9110 section 2.5).
+        _ => false,
+    }
 }
@@ -1,17 +1,166 @@
+use std::fmt::{Debug, Formatter};
+
 use serde::Serialize;
 
+use crate::extract::Bytes;
+use crate::headers::Headers;
+
+const CONTENT_TYPE_HEADER: &str = "Content-Type";
+const JSON_CONTENT_TYPE: &str = "application/json";
+const BINARY_CONTENT_TYPE: &str = "application/octet-stream";
+
 #[derive(Debug)]
 pub struct HttpResponse {
     pub status: HttpStatus,
-    pub body: Option<String>,
+    pub headers: Headers,
+    pub body: Option<Vec<u8>>,
+    /// Body produced incrementally, sent with `Transfer-Encoding: chunked`. Takes
+    /// precedence over `body` when set.
+    pub chunked_body: Option<ChunkedBody>,
 }
 
-#[derive(Debug)]
-pub enum HttpStatus {
-    Ok,
-    BadRequest,
-    NotFound,
-    InternalServerError,
+pub struct ChunkedBody(Box<dyn Iterator<Item = Vec<u8>> + Send>);
+
+/// Converts handler results into the response written to the client.
+pub trait IntoHttpResponse {
+    fn into_http_response(self) -> HttpResponse;
+}
+
+/// Declares `HttpStatus` together with its code/reason lookups so the variants,
+/// `get_status_info` and the `u16` conversions can't get out of sync.
+macro_rules! http_statuses {
+    ($($variant:ident = $code:literal, $text:literal;)+) => {
+        #[derive(Debug, Clone, PartialEq, Eq)]
+        pub enum HttpStatus {
+            $($variant,)+
+            /// Status code outside of the IANA registry, with its reason phrase. The code
+            /// has to have three digits and the reason can't contain control characters,
+            /// see `is_valid`.
+            Custom(u16, String),
+        }
+
+        impl HttpStatus {
+            pub fn get_status_info(&self) -> HttpStatusDescription {
+                match self {
+                    $(HttpStatus::$variant => HttpStatusDescription {
+                        status_code: $code,
+                        status_text: String::from($text),
+                    },)+
+                    HttpStatus::Custom(code, text) => HttpStatusDescription {
+                        status_code: *code as usize,
+                        status_text: text.clone(),
+                    },
+                }
+            }
+
+            pub fn code(&self) -> u16 {
+                match self {
+                    $(HttpStatus::$variant => $code,)+
+                    HttpStatus::Custom(code, _) => *code,
+                }
+            }
+        }
+
+        impl From<u16> for HttpStatus {
+            /// Unregistered codes become `Custom` with an empty reason phrase.
+            fn from(code: u16) -> HttpStatus {
+                match code {
+                    $($code => HttpStatus::$variant,)+
+                    _ => HttpStatus::Custom(code, String::new()),
+                }
+            }
+        }
+    };
+}
+
+http_statuses! {
+    Continue = 100, "Continue";
+    SwitchingProtocols = 101, "Switching Protocols";
+    Processing = 102, "Processing";
+    EarlyHints = 103, "Early Hints";
+    Ok = 200, "OK";
+    Created = 201, "Created";
+    Accepted = 202, "Accepted";
+    NonAuthoritativeInformation = 203, "Non-Authoritative Information";
+    NoContent = 204, "No Content";
+    ResetContent = 205, "Reset Content";
+    PartialContent = 206, "Partial Content";
+    MultiStatus = 207, "Multi-Status";
+    AlreadyReported = 208, "Already Reported";
+    ImUsed = 226, "IM Used";
+    MultipleChoices = 300, "Multiple Choices";
+    MovedPermanently = 301, "Moved Permanently";
+    Found = 302, "Found";
+    SeeOther = 303, "See Other";
+    NotModified = 304, "Not Modified";
+    UseProxy = 305, "Use Proxy";
+    TemporaryRedirect = 307, "Temporary Redirect";
+    PermanentRedirect = 308, "Permanent Redirect";
+    BadRequest = 400, "Bad Request";
+    Unauthorized = 401, "Unauthorized";
+    PaymentRequired = 402, "Payment Required";
+    Forbidden = 403, "Forbidden";
+    NotFound = 404, "Not Found";
+    MethodNotAllowed = 405, "Method Not Allowed";
+    NotAcceptable = 406, "Not Acceptable";
+    ProxyAuthenticationRequired = 407, "Proxy Authentication Required";
+    RequestTimeout = 408, "Request Timeout";
+    Conflict = 409, "Conflict";
+    Gone = 410, "Gone";
+    LengthRequired = 411, "Length Required";
+    PreconditionFailed = 412, "Precondition Failed";
+    ContentTooLarge = 413, "Content Too Large";
+    UriTooLong = 414, "URI Too Long";
+    UnsupportedMediaType = 415, "Unsupported Media Type";
+    RangeNotSatisfiable = 416, "Range Not Satisfiable";
+    ExpectationFailed = 417, "Expectation Failed";
+    MisdirectedRequest = 421, "Misdirected Request";
+    UnprocessableContent = 422, "Unprocessable Content";
+    Locked = 423, "Locked";
+    FailedDependency = 424, "Failed Dependency";
+    TooEarly = 425, "Too Early";
+    UpgradeRequired = 426, "Upgrade Required";
+    PreconditionRequired = 428, "Precondition Required";
+    TooManyRequests = 429, "Too Many Requests";
+    RequestHeaderFieldsTooLarge = 431, "Request Header Fields Too Large";
+    UnavailableForLegalReasons = 451, "Unavailable For Legal Reasons";
+    InternalServerError = 500, "Internal Server Error";
+    NotImplemented = 501, "Not Implemented";
+    BadGateway = 502, "Bad Gateway";
+    ServiceUnavailable = 503, "Service Unavailable";
+    GatewayTimeout = 504, "Gateway Timeout";
+    HttpVersionNotSupported = 505, "HTTP Version Not Supported";
+    VariantAlsoNegotiates = 506, "Variant Also Negotiates";
+    InsufficientStorage = 507, "Insufficient Storage";
+    LoopDetected = 508, "Loop Detected";
+    NotExtended = 510, "Not Extended";
+    NetworkAuthenticationRequired = 511, "Network Authentication Required";
+}
+
+impl HttpStatus {
+    /// Whether the status can be written into the status line. Invalid custom statuses
+    /// are answered with 500 instead, the status line would break or let the reason
+    /// phrase add headers.
+    pub fn is_valid(&self) -> bool {
+        match self {
+            HttpStatus::Custom(code, text) => {
+                (100..=999).contains(code) && !text.chars().any(|c| c.is_control() && c != '\t')
+            }
+            _ => true,
+        }
+    }
+}
+
+impl From<&HttpStatus> for u16 {
+    fn from(status: &HttpStatus) -> u16 {
+        status.code()
+    }
+}
+
+impl From<HttpStatus> for u16 {
+    fn from(status: HttpStatus) -> u16 {
+        status.code()
+    }
 }
 
 #[derive(Debug)]
@@ -21,41 +170,262 @@ pub struct HttpStatusDescription {
 }
 
 impl HttpResponse {
+    pub fn new(status: HttpStatus, body: Option<String>) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: Headers::new(),
+            body: body.map(String::into_bytes),
+            chunked_body: None,
+        }
+    }
+
     pub fn ok(body: Option<String>) -> HttpResponse {
+        HttpResponse::new(HttpStatus::Ok, body)
+    }
+
+    /// Binary body sent as it is, e.g. an image. No `Content-Type` is set, add one with
+    /// `header`.
+    pub fn bytes(status: HttpStatus, body: impl Into<Vec<u8>>) -> HttpResponse {
         HttpResponse {
-            status: HttpStatus::Ok,
-            body,
+            status,
+            headers: Headers::new(),
+            body: Some(body.into()),
+            chunked_body: None,
+        }
+    }
+
+    /// Response which body is streamed to the client chunk by chunk as the iterator
+    /// produces them. Chunks can be text (`String`, `&str`) or bytes (`Vec<u8>`).
+    pub fn chunked<I>(status: HttpStatus, chunks: I) -> HttpResponse
+    where
+        I: IntoIterator,
+        I::Item: Into<Vec<u8>> + 'static,
+        I::IntoIter: Send + 'static,
+    {
+        HttpResponse {
+            status,
+            headers: Headers::new(),
+            body: None,
+            chunked_body: Some(ChunkedBody::new(chunks)),
         }
     }
+
+    /// Headers which name isn't a token or which value contains CR, LF or NUL are
+    /// dropped when the response is written.
+    pub fn header(mut self, name: &str, value: &str) -> HttpResponse {
+        self.headers.append(name, value);
+        self
+    }
 }
 
-impl HttpStatus {
-    pub fn get_status_info(&self) -> HttpStatusDescription {
-        match self {
-            HttpStatus::Ok => HttpStatusDescription {
-                status_code: 200,
-                status_text: String::from("OK"),
-            },
-            HttpStatus::BadRequest => HttpStatusDescription {
-                status_code: 400,
-                status_text: String::from("Bad Request"),
-            },
-            HttpStatus::NotFound => HttpStatusDescription {
-                status_code: 404,
-                status_text: String::from("Not Found"),
-            },
-            HttpStatus::InternalServerError => HttpStatusDescription {
-                status_code: 500,
-                status_text: String::from("Internal Server Error"),
-            },
+/// Handler result which `data` is serialized into a json body, `Response<Bytes>` sends
+/// its bytes as they are.
+pub struct Response<T> {
+    pub status: HttpStatus,
+    pub headers: Headers,
+    pub data: Option<T>,
+}
+
+impl<T> Response<T> {
+    pub fn new(status: HttpStatus, data: Option<T>) -> Response<T> {
+        Response {
+            status,
+            headers: Headers::new(),
+            data,
         }
     }
+
+    pub fn ok(data: T) -> Response<T> {
+        Response::new(HttpStatus::Ok, Some(data))
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Response<T> {
+        self.headers.append(name, value);
+        self
+    }
+}
+
+impl Response<Bytes> {
+    /// Binary body, e.g. an image or a file download. `Content-Type` is
+    /// `application/octet-stream` unless the handler sets its own.
+    pub fn bytes(data: impl Into<Vec<u8>>) -> Response<Bytes> {
+        Response::ok(Bytes(data.into()))
+    }
 }
 
-pub struct Response<T>
+impl ChunkedBody {
+    pub fn new<I>(chunks: I) -> ChunkedBody
+    where
+        I: IntoIterator,
+        I::Item: Into<Vec<u8>> + 'static,
+        I::IntoIter: Send + 'static,
+    {
+        ChunkedBody(Box::new(chunks.into_iter().map(Into::into)))
+    }
+}
+
+impl Iterator for ChunkedBody {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        self.0.next()
+    }
+}
+
+impl Debug for ChunkedBody {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ChunkedBody")
+    }
+}
+
+impl IntoHttpResponse for HttpResponse {
+    fn into_http_response(self) -> HttpResponse {
+        self
+    }
+}
+
+impl<T> IntoHttpResponse for Response<T>
 where
     T: Serialize,
 {
-    pub status: HttpStatus,
-    pub data: Option<T>,
+    /// Serializes `data` into a json body. `Content-Type` is only set if the handler
+    /// didn't provide its own.
+    fn into_http_response(self) -> HttpResponse {
+        let mut headers = self.headers;
+
+        let body = match self.data {
+            Some(data) => match serde_json::to_vec(&data) {
+                Ok(body) => Some(body),
+                Err(_) => {
+                    return HttpResponse::new(
+                        HttpStatus::InternalServerError,
+                        Some("Unable to serialize response".to_string()),
+                    )
+                }
+            },
+            None => None,
+        };
+
+        if body.is_some() && !headers.contains(CONTENT_TYPE_HEADER) {
+            headers.append(CONTENT_TYPE_HEADER, JSON_CONTENT_TYPE);
+        }
+
+        HttpResponse {
+            status: self.status,
+            headers,
+            body,
+            chunked_body: None,
+        }
+    }
+}
+
+impl IntoHttpResponse for Response<Bytes> {
+    fn into_http_response(self) -> HttpResponse {
+        let mut headers = self.headers;
+        let body = self.data.map(|data| data.0);
+
+        if body.is_some() && !headers.contains(CONTENT_TYPE_HEADER) {
+            headers.append(CONTENT_TYPE_HEADER, BINARY_CONTENT_TYPE);
+        }
+
+        HttpResponse {
+            status: self.status,
+            headers,
+            body,
+            chunked_body: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::extract::Bytes;
+
+    use super::{HttpStatus, IntoHttpResponse, Response};
+
+    #[test]
+    fn status_converts_to_and_from_u16() {
+        assert_eq!(HttpStatus::from(201), HttpStatus::Created);
+        assert_eq!(HttpStatus::from(429), HttpStatus::TooManyRequests);
+        assert_eq!(u16::from(HttpStatus::PermanentRedirect), 308);
+        assert_eq!(
+            HttpStatus::from(299),
+            HttpStatus::Custom(299, String::new())
+        );
+        assert_eq!(u16::from(&HttpStatus::Custom(599, "Oops".to_string())), 599);
+    }
+
+    #[test]
+    fn custom_status_info_uses_given_reason() {
+        let info = HttpStatus::Custom(599, "Network Connect Timeout".to_string()).get_status_info();
+
+        assert_eq!(info.status_code, 599);
+        assert_eq!(info.status_text, "Network Connect Timeout");
+        assert_eq!(
+            HttpStatus::UnprocessableContent
+                .get_status_info()
+                .status_text,
+            "Unprocessable Content"
+        );
+    }
+
+    #[test]
+    fn custom_status_has_to_fit_status_line() {
+        assert!(HttpStatus::Custom(599, "Network Connect Timeout".to_string()).is_valid());
+        assert!(HttpStatus::Custom(299, String::new()).is_valid());
+        assert!(!HttpStatus::Custom(5, "Oops".to_string()).is_valid());
+        assert!(!HttpStatus::Custom(1000, "Oops".to_string()).is_valid());
+        assert!(!HttpStatus::Custom(599, "Oops\r\nSet-Cookie: a=b".to_string()).is_valid());
+        assert!(HttpStatus::NotFound.is_valid());
+    }
+
+    #[test]
+    fn into_http_response_sets_json_content_type() {
+        let response = Response::ok(vec![1, 2, 3])
+            .header("X-Foo", "bar")
+            .into_http_response();
+
+        assert_eq!(response.body.as_deref(), Some(&b"[1,2,3]"[..]));
+        assert_eq!(
+            response.headers.get("content-type"),
+            Some("application/json")
+        );
+        assert_eq!(response.headers.get("x-foo"), Some("bar"));
+    }
+
+    #[test]
+    fn into_http_response_keeps_custom_content_type() {
+        let response = Response::ok("<p>hi</p>")
+            .header("Content-Type", "text/html")
+            .into_http_response();
+
+        assert_eq!(response.headers.get_all("content-type"), vec!["text/html"]);
+    }
+
+    #[test]
+    fn into_http_response_without_data_has_no_content_type() {
+        let response = Response::<String>::new(HttpStatus::NotFound, None).into_http_response();
+
+        assert_eq!(response.body, None);
+        assert!(!response.headers.contains("content-type"));
+    }
+
+    #[test]
+    fn bytes_response_is_sent_as_is() {
+        let png = vec![0x89, b'P', b'N', b'G', 0xff];
+        let response = Response::bytes(png.clone()).into_http_response();
+
+        assert_eq!(response.body, Some(png));
+        assert_eq!(
+            response.headers.get("content-type"),
+            Some("application/octet-stream")
+        );
+
+        let response = Response::new(HttpStatus::PartialContent, Some(Bytes(vec![1, 2])))
+            .header("Content-Type", "image/png")
+            .into_http_response();
+
+        assert_eq!(response.status, HttpStatus::PartialContent);
+        assert_eq!(response.headers.get_all("content-type"), vec!["image/png"]);
+    }
 }
@@ -1,7 +1,26 @@
+pub mod access_log;
+pub mod app_state;
+#[cfg(feature = "tokio")]
+mod async_connection;
+mod connection;
+pub mod extract;
+pub mod handler;
+pub mod headers;
 pub mod http_handler_info;
-mod http_request;
+pub mod http_request;
 pub mod http_response;
-mod method_verb;
+pub mod listener;
+pub mod method_verb;
+pub mod middleware;
+mod params_deserializer;
+mod path_normalize;
+mod percent_decode;
 mod request_parser;
+mod route_constraint;
+mod router;
+pub mod shutdown;
+mod thread_pool;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod uri_params;
 pub mod web_api;
@@ -0,0 +1,431 @@
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::{
+    fs::FileTypeExt,
+    io::{FromRawFd, OwnedFd, RawFd},
+    net::{UnixListener, UnixStream},
+};
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::{
+    env, fs,
+    ops::Range,
+    path::Path,
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+#[cfg(feature = "tls")]
+use crate::tls::{TlsAcceptor, TlsStream};
+
+const LISTEN_BACKLOG: i32 = 1024;
+/// First descriptor passed by the service manager, see `sd_listen_fds(3)`.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Address one of the server's listeners is bound to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    /// Path of a Unix domain socket, empty for an unnamed one.
+    Unix(PathBuf),
+}
+
+/// Address added to the `WebApi` builder, bound when the server starts.
+pub(crate) enum BindAddr {
+    /// Addresses the `ToSocketAddrs` value resolved to, the first one which can be
+    /// bound is used like in `TcpListener::bind`.
+    Tcp(Result<Vec<SocketAddr>>),
+    #[cfg(feature = "tls")]
+    Tls(Result<Vec<SocketAddr>>),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+/// Bound listener the server accepts connections on.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(feature = "tls")]
+    Tls(TcpListener, TlsAcceptor),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// Accepted connection, TLS sessions do the handshake on the first read.
+pub(crate) enum Connection {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{addr}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl BindAddr {
+    pub fn tcp<A: ToSocketAddrs>(addr: A) -> BindAddr {
+        BindAddr::Tcp(resolve(addr))
+    }
+}
+
+impl Listener {
+    pub fn accept(&self) -> Result<Connection> {
+        match self {
+            Listener::Tcp(listener) => Ok(Connection::Tcp(listener.accept()?.0)),
+            #[cfg(feature = "tls")]
+            Listener::Tls(listener, acceptor) => {
+                let stream = acceptor.accept(listener.accept()?.0)?;
+
+                Ok(Connection::Tls(Box::new(stream)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => Ok(Connection::Unix(listener.accept()?.0)),
+        }
+    }
+
+    pub fn local_addr(&self) -> Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => Ok(ListenAddr::Tcp(listener.local_addr()?)),
+            #[cfg(feature = "tls")]
+            Listener::Tls(listener, _) => Ok(ListenAddr::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let addr = listener.local_addr()?;
+                let path = addr.as_pathname().map(Path::to_path_buf);
+
+                Ok(ListenAddr::Unix(path.unwrap_or_default()))
+            }
+        }
+    }
+}
+
+/// Whether an accept error comes from a single connection, e.g. a client which reset
+/// it before it was accepted. Others, like running out of file descriptors (EMFILE),
+/// would be returned again right away.
+pub(crate) fn is_connection_error(error: &Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::ConnectionAborted
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::Interrupted
+            | ErrorKind::WouldBlock
+    )
+}
+
+pub(crate) fn resolve<A: ToSocketAddrs>(addr: A) -> Result<Vec<SocketAddr>> {
+    Ok(addr.to_socket_addrs()?.collect())
+}
+
+/// Binds the first of the resolved addresses which can be bound. IPv6 listeners only
+/// accept IPv6 clients, so `[::]` and `0.0.0.0` can listen on the same port.
+pub(crate) fn bind_tcp(addrs: &Result<Vec<SocketAddr>>) -> Result<TcpListener> {
+    let addrs = match addrs {
+        Ok(addrs) => addrs,
+        Err(e) => return Err(Error::new(e.kind(), e.to_string())),
+    };
+
+    let mut last_error = None;
+
+    for addr in addrs {
+        match bind_socket(*addr) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "could not resolve to any addresses",
+        )
+    }))
+}
+
+fn bind_socket(addr: SocketAddr) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+
+    // Same as `TcpListener::bind`, a restarted server doesn't wait for TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+
+    Ok(socket.into())
+}
+
+/// Binds a Unix domain socket. A socket file left behind by a server which didn't stop
+/// cleanly is removed first, one a running server still accepts on fails with
+/// `AddrInUse`.
+#[cfg(unix)]
+pub(crate) fn bind_unix(path: &Path) -> Result<UnixListener> {
+    let is_socket =
+        fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket());
+
+    if is_socket {
+        if UnixStream::connect(path).is_ok() {
+            return Err(Error::new(
+                ErrorKind::AddrInUse,
+                format!("{} is in use", path.display()),
+            ));
+        }
+
+        fs::remove_file(path)?;
+    }
+
+    UnixListener::bind(path)
+}
+
+/// Listening sockets passed by the service manager with `LISTEN_FDS`, `None` if the
+/// variables are missing or meant for another process. The sockets are taken only once
+/// per process. The variables are left as they are, changing the environment isn't
+/// safe once other threads may be reading it, and child processes don't inherit the
+/// sockets since they are marked close-on-exec.
+#[cfg(unix)]
+pub(crate) fn listen_fds() -> Result<Option<Vec<Listener>>> {
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+
+    let pid = env::var("LISTEN_PID").ok();
+    let count = env::var("LISTEN_FDS").ok();
+    let fds = passed_fds(pid.as_deref(), count.as_deref(), process::id());
+
+    if fds.is_empty() || TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(None);
+    }
+
+    fds.map(inherited_listener).collect::<Result<_>>().map(Some)
+}
+
+/// Descriptors passed to the process `own_pid` according to the values of `LISTEN_PID`
+/// and `LISTEN_FDS`, empty if there are none.
+#[cfg(unix)]
+fn passed_fds(pid: Option<&str>, count: Option<&str>, own_pid: u32) -> Range<RawFd> {
+    let pid = pid.and_then(|pid| pid.parse().ok());
+    let count = count.and_then(|count| count.parse().ok()).unwrap_or(0);
+
+    if pid != Some(own_pid) || count <= 0 {
+        return LISTEN_FDS_START..LISTEN_FDS_START;
+    }
+
+    LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count)
+}
+
+#[cfg(unix)]
+fn inherited_listener(fd: RawFd) -> Result<Listener> {
+    // SAFETY: LISTEN_PID says the descriptors were passed to this process, and they are
+    // taken only once.
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    socket.set_cloexec(true)?;
+
+    if socket.r#type()? != Type::STREAM {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("passed descriptor {fd} isn't a stream socket"),
+        ));
+    }
+
+    match socket.local_addr()?.domain() {
+        Domain::IPV4 | Domain::IPV6 => Ok(Listener::Tcp(socket.into())),
+        Domain::UNIX => Ok(Listener::Unix(OwnedFd::from(socket).into())),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("passed descriptor {fd} isn't a TCP or Unix domain socket"),
+        )),
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub(crate) use self::tokio_listener::{accept_any, AsyncConnection, AsyncListener};
+
+#[cfg(feature = "tokio")]
+mod tokio_listener {
+    use std::future::poll_fn;
+    use std::io::Result;
+    #[cfg(all(feature = "tls", not(feature = "tokio-tls")))]
+    use std::io::{Error, ErrorKind};
+    use std::net::SocketAddr;
+    use std::task::{Context, Poll};
+
+    use tokio::net::{TcpListener, TcpStream};
+    #[cfg(unix)]
+    use tokio::net::{UnixListener, UnixStream};
+
+    use super::Listener;
+
+    /// `Listener` registered with the tokio runtime.
+    pub(crate) enum AsyncListener {
+        Tcp(TcpListener),
+        #[cfg(feature = "tokio-tls")]
+        Tls(TcpListener, tokio_rustls::TlsAcceptor),
+        #[cfg(unix)]
+        Unix(UnixListener),
+    }
+
+    pub(crate) enum AsyncConnection {
+        Tcp(TcpStream, SocketAddr),
+        #[cfg(feature = "tokio-tls")]
+        Tls(TcpStream, tokio_rustls::TlsAcceptor),
+        #[cfg(unix)]
+        Unix(UnixStream),
+    }
+
+    impl AsyncListener {
+        /// Has to be called on a tokio runtime.
+        pub fn from_std(listener: Listener) -> Result<AsyncListener> {
+            match listener {
+                Listener::Tcp(listener) => {
+                    listener.set_nonblocking(true)?;
+                    Ok(AsyncListener::Tcp(TcpListener::from_std(listener)?))
+                }
+                #[cfg(feature = "tokio-tls")]
+                Listener::Tls(listener, acceptor) => {
+                    listener.set_nonblocking(true)?;
+                    let listener = TcpListener::from_std(listener)?;
+
+                    Ok(AsyncListener::Tls(listener, acceptor.async_acceptor()))
+                }
+                #[cfg(all(feature = "tls", not(feature = "tokio-tls")))]
+                Listener::Tls(..) => Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "TLS listeners on the async server need the `tokio-tls` feature",
+                )),
+                #[cfg(unix)]
+                Listener::Unix(listener) => {
+                    listener.set_nonblocking(true)?;
+                    Ok(AsyncListener::Unix(UnixListener::from_std(listener)?))
+                }
+            }
+        }
+
+        fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<Result<AsyncConnection>> {
+            match self {
+                AsyncListener::Tcp(listener) => listener
+                    .poll_accept(cx)
+                    .map_ok(|(stream, client)| AsyncConnection::Tcp(stream, client)),
+                #[cfg(feature = "tokio-tls")]
+                AsyncListener::Tls(listener, acceptor) => listener
+                    .poll_accept(cx)
+                    .map_ok(|(stream, _)| AsyncConnection::Tls(stream, acceptor.clone())),
+                #[cfg(unix)]
+                AsyncListener::Unix(listener) => listener
+                    .poll_accept(cx)
+                    .map_ok(|(stream, _)| AsyncConnection::Unix(stream)),
+            }
+        }
+    }
+
+    /// Next connection accepted by any of the listeners.
+    pub(crate) async fn accept_any(listeners: &[AsyncListener]) -> Result<AsyncConnection> {
+        poll_fn(|cx| {
+            for listener in listeners {
+                if let Poll::Ready(accepted) = listener.poll_accept(cx) {
+                    return Poll::Ready(accepted);
+                }
+            }
+
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::net::UnixListener;
+    use std::{env, fs, process};
+
+    use super::{bind_unix, is_connection_error, listen_fds, passed_fds, Listener, OwnedFd};
+
+    #[test]
+    fn passed_fds_are_only_for_the_process_named() {
+        assert_eq!(passed_fds(Some("42"), Some("2"), 42), 3..5);
+        assert_eq!(passed_fds(Some("42"), Some("2"), 43), 3..3);
+        assert_eq!(passed_fds(None, Some("2"), 42), 3..3);
+        assert_eq!(passed_fds(Some("42"), None, 42), 3..3);
+        assert_eq!(passed_fds(Some("42"), Some("0"), 42), 3..3);
+        assert_eq!(passed_fds(Some("42"), Some("-1"), 42), 3..3);
+        assert_eq!(passed_fds(Some("42"), Some("x"), 42), 3..3);
+    }
+
+    #[test]
+    fn listen_fds_adopts_the_socket_passed_as_fd_3() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The shell moves the listener from stdin to fd 3 and keeps its pid for the
+        // test binary it execs, so `LISTEN_PID` names the child.
+        let status = process::Command::new("sh")
+            .arg("-c")
+            .arg("exec 3<&0 0</dev/null; LISTEN_PID=$$ LISTEN_FDS=1 exec \"$0\" \"$@\"")
+            .arg(env::current_exe().unwrap())
+            .args([
+                "listener::tests::listen_fds_child",
+                "--exact",
+                "--test-threads=1",
+            ])
+            .env("SUGONDESE_LISTEN_FDS_ADDR", addr.to_string())
+            .stdin(OwnedFd::from(listener))
+            .stdout(process::Stdio::null())
+            .status()
+            .unwrap();
+
+        assert!(status.success());
+    }
+
+    /// Runs in the child process of `listen_fds_adopts_the_socket_passed_as_fd_3`.
+    #[test]
+    fn listen_fds_child() {
+        let Ok(addr) = env::var("SUGONDESE_LISTEN_FDS_ADDR") else {
+            return;
+        };
+
+        match listen_fds().unwrap().as_deref() {
+            Some([Listener::Tcp(listener)]) => {
+                assert_eq!(listener.local_addr().unwrap().to_string(), addr);
+            }
+            _ => panic!("fd 3 wasn't adopted"),
+        }
+
+        assert!(listen_fds().unwrap().is_none());
+        assert!(env::var("LISTEN_FDS").is_ok());
+    }
+
+    #[test]
+    fn running_out_of_file_descriptors_isnt_a_connection_error() {
+        // EMFILE and ENFILE.
+        assert!(!is_connection_error(&Error::from_raw_os_error(24)));
+        assert!(!is_connection_error(&Error::from_raw_os_error(23)));
+        assert!(is_connection_error(&ErrorKind::ConnectionAborted.into()));
+    }
+
+    #[test]
+    fn stale_unix_socket_is_replaced() {
+        let path = env::temp_dir().join(format!("sugondese-{}.sock", process::id()));
+        let _ = fs::remove_file(&path);
+
+        drop(UnixListener::bind(&path).unwrap());
+        let listener = bind_unix(&path).unwrap();
+
+        assert_eq!(bind_unix(&path).unwrap_err().kind(), ErrorKind::AddrInUse);
+
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,56 @@
+use std::cell::Cell;
+
+use crate::http_request::HttpRequest;
+use crate::http_response::HttpResponse;
+use crate::web_api::{dispatch, Endpoints};
+
+/// Layer added with `WebApi::wrap`, called for every request before it's routed.
+/// It may change the request before passing it to `next`, answer without calling
+/// `next` at all, or change the response `next` returns. Implemented for closures
+/// taking `(HttpRequest, Next)`.
+pub trait Middleware {
+    fn handle(&self, request: HttpRequest, next: Next<'_>) -> HttpResponse;
+}
+
+/// Rest of the chain: the middlewares added after the current one and the router.
+pub struct Next<'a> {
+    endpoints: &'a Endpoints,
+    idx: usize,
+    matched_route: &'a Cell<Option<String>>,
+}
+
+impl<'a> Next<'a> {
+    pub(crate) fn new(
+        endpoints: &'a Endpoints,
+        matched_route: &'a Cell<Option<String>>,
+    ) -> Next<'a> {
+        Next {
+            endpoints,
+            idx: 0,
+            matched_route,
+        }
+    }
+
+    pub fn run(self, request: HttpRequest) -> HttpResponse {
+        match self.endpoints.middlewares.get(self.idx) {
+            Some(middleware) => middleware.handle(
+                request,
+                Next {
+                    endpoints: self.endpoints,
+                    idx: self.idx + 1,
+                    matched_route: self.matched_route,
+                },
+            ),
+            None => dispatch(request, self.endpoints, self.matched_route),
+        }
+    }
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(HttpRequest, Next<'_>) -> HttpResponse,
+{
+    fn handle(&self, request: HttpRequest, next: Next<'_>) -> HttpResponse {
+        self(request, next)
+    }
+}
@@ -0,0 +1,237 @@
+use serde::de::value::{Error, MapDeserializer, SeqDeserializer};
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+
+/// Deserializes string key/value pairs (route or query params) into a struct, parsing
+/// values into the field types, e.g. `"42"` into `u64` or `"true"` into `bool`.
+/// Repeated keys are collected into sequence fields (`Vec<T>`), scalar fields take
+/// the first value.
+pub fn from_params<'a, T, I>(params: I) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+    I: Iterator<Item = (&'a str, &'a str)>,
+{
+    let mut grouped: Vec<(&str, Vec<&str>)> = Vec::new();
+
+    for (key, value) in params {
+        match grouped.iter_mut().find(|(existing, _)| *existing == key) {
+            Some((_, values)) => values.push(value),
+            None => grouped.push((key, vec![value])),
+        }
+    }
+
+    let deserializer = MapDeserializer::new(
+        grouped
+            .into_iter()
+            .map(|(key, values)| (key, ValueDeserializer(values))),
+    );
+
+    T::deserialize(deserializer)
+}
+
+/// All values of a param, never empty.
+struct ValueDeserializer<'a>(Vec<&'a str>);
+
+impl<'a> ValueDeserializer<'a> {
+    fn value(&self) -> &'a str {
+        self.0[0]
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)+) => {
+        $(fn $method<V>(self, visitor: V) -> Result<V::Value, Error>
+        where
+            V: Visitor<'de>,
+        {
+            match self.value().parse() {
+                Ok(value) => visitor.$visit(value),
+                Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(self.value()), &visitor)),
+            }
+        })+
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'de> {
+    type Error = Error;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_borrowed_str(self.value())
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_unit<V>(self, visitor: V) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_enum(self.value().into_deserializer())
+    }
+
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value, Error>
+    where
+        V: Visitor<'de>,
+    {
+        let values = self
+            .0
+            .into_iter()
+            .map(|value| ValueDeserializer(vec![value]));
+
+        visitor.visit_seq(SeqDeserializer::new(values))
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 str string bytes byte_buf unit_struct tuple tuple_struct map
+        struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for ValueDeserializer<'de> {
+    type Deserializer = ValueDeserializer<'de>;
+
+    fn into_deserializer(self) -> ValueDeserializer<'de> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::from_params;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum Order {
+        Asc,
+        Desc,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Paging {
+        page: u32,
+        size: Option<u8>,
+        order: Order,
+        name: String,
+        active: bool,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Filter {
+        tag: Vec<String>,
+        id: Option<Vec<u32>>,
+        page: u32,
+    }
+
+    #[test]
+    fn from_params_parses_field_types() {
+        let params = [
+            ("page", "3"),
+            ("order", "desc"),
+            ("name", "42"),
+            ("active", "true"),
+        ];
+
+        let paging: Paging = from_params(params.into_iter()).unwrap();
+
+        assert_eq!(
+            paging,
+            Paging {
+                page: 3,
+                size: None,
+                order: Order::Desc,
+                name: "42".to_string(),
+                active: true,
+            }
+        );
+    }
+
+    #[test]
+    fn from_params_reports_invalid_values() {
+        let params = [
+            ("page", "three"),
+            ("order", "desc"),
+            ("name", "x"),
+            ("active", "true"),
+        ];
+
+        let error = from_params::<Paging, _>(params.into_iter()).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "invalid value: string \"three\", expected u32"
+        );
+
+        let error = from_params::<Paging, _>([("page", "1")].into_iter()).unwrap_err();
+
+        assert_eq!(error.to_string(), "missing field `order`");
+    }
+
+    #[test]
+    fn from_params_collects_repeated_keys() {
+        let params = [("tag", "a"), ("page", "1"), ("tag", "b"), ("page", "2")];
+
+        let filter: Filter = from_params(params.into_iter()).unwrap();
+
+        assert_eq!(
+            filter,
+            Filter {
+                tag: vec!["a".to_string(), "b".to_string()],
+                id: None,
+                page: 1,
+            }
+        );
+
+        let filter: Filter =
+            from_params([("tag", "a"), ("id", "7"), ("page", "1")].into_iter()).unwrap();
+
+        assert_eq!(filter.tag, vec!["a".to_string()]);
+        assert_eq!(filter.id, Some(vec![7]));
+    }
+}
@@ -0,0 +1,123 @@
+use std::borrow::Cow;
+
+use crate::percent_decode::{decode_hex_pair, percent_decode};
+
+/// Collapses duplicate slashes and removes `.` and `..` segments (RFC 3986, section
+/// 5.2.4), `..` never goes above the root. Segments stay percent-encoded, except that
+/// encoded dot segments (`%2E%2E`) are removed too and escaped unreserved characters
+/// are decoded (`/%61dmin` is `/admin`). `None` if a segment decodes to
+/// something containing `/`, `\` or NUL, so `..%2F..%2Fetc` can't turn into a path
+/// traversal once the route params are decoded. Paths which don't start with `/`
+/// (`OPTIONS *`) are left as they are.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let Some(raw_segments) = path.strip_prefix('/') else {
+        return Some(path.to_string());
+    };
+
+    let mut segments: Vec<Cow<str>> = vec![];
+    let mut trailing_slash = false;
+
+    for segment in raw_segments.split('/') {
+        let decoded = percent_decode(segment);
+
+        if decoded.contains(['/', '\\', '\0']) {
+            return None;
+        }
+
+        trailing_slash = true;
+
+        match decoded.as_ref() {
+            "" | "." => (),
+            ".." => {
+                segments.pop();
+            }
+            _ => {
+                segments.push(decode_unreserved(segment));
+                trailing_slash = false;
+            }
+        }
+    }
+
+    let mut normalized = format!("/{}", segments.join("/"));
+
+    if trailing_slash && !segments.is_empty() {
+        normalized.push('/');
+    }
+
+    Some(normalized)
+}
+
+/// Decodes escapes of letters, digits and `-._~` (RFC 3986, section 6.2.2.2), they
+/// mean the same encoded or not.
+fn decode_unreserved(segment: &str) -> Cow<'_, str> {
+    if !segment.contains('%') {
+        return Cow::Borrowed(segment);
+    }
+
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+
+    while idx < bytes.len() {
+        let unreserved = match bytes[idx] {
+            b'%' => bytes
+                .get(idx + 1..idx + 3)
+                .and_then(decode_hex_pair)
+                .filter(|byte| byte.is_ascii_alphanumeric() || b"-._~".contains(byte)),
+            _ => None,
+        };
+
+        match unreserved {
+            Some(byte) => {
+                decoded.push(byte);
+                idx += 3;
+            }
+            None => {
+                decoded.push(bytes[idx]);
+                idx += 1;
+            }
+        }
+    }
+
+    // Only ASCII escapes were replaced, the rest is the valid UTF-8 of `segment`.
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::normalize_path;
+
+    #[test]
+    fn normalize_path_removes_dot_segments_and_duplicate_slashes() {
+        assert_eq!(normalize_path("/a//b").as_deref(), Some("/a/b"));
+        assert_eq!(normalize_path("/a/./b/").as_deref(), Some("/a/b/"));
+        assert_eq!(normalize_path("/a/c/../b").as_deref(), Some("/a/b"));
+        assert_eq!(normalize_path("/a/b/..").as_deref(), Some("/a/"));
+        assert_eq!(normalize_path("/../../a").as_deref(), Some("/a"));
+        assert_eq!(normalize_path("/a/%2e%2E/b").as_deref(), Some("/b"));
+        assert_eq!(normalize_path("//").as_deref(), Some("/"));
+        assert_eq!(
+            normalize_path("/files/my%20doc").as_deref(),
+            Some("/files/my%20doc")
+        );
+        assert_eq!(normalize_path("*").as_deref(), Some("*"));
+    }
+
+    #[test]
+    fn normalize_path_decodes_unreserved_characters() {
+        assert_eq!(normalize_path("/%61dmin").as_deref(), Some("/admin"));
+        assert_eq!(normalize_path("/a%2D%5fb%7E").as_deref(), Some("/a-_b~"));
+        assert_eq!(normalize_path("/%2561dmin").as_deref(), Some("/%2561dmin"));
+        assert_eq!(
+            normalize_path("/%D0%BF%3F%20").as_deref(),
+            Some("/%D0%BF%3F%20")
+        );
+    }
+
+    #[test]
+    fn normalize_path_rejects_encoded_separators() {
+        assert_eq!(normalize_path("/static/..%2F..%2Fetc/passwd"), None);
+        assert_eq!(normalize_path("/static/..%5C..%5Cwin.ini"), None);
+        assert_eq!(normalize_path("/static/a%00"), None);
+    }
+}
@@ -0,0 +1,90 @@
+use std::borrow::Cow;
+
+/// Decodes `%XX` escapes (RFC 3986). Malformed escapes are kept as they are and byte
+/// sequences that aren't valid UTF-8 are replaced with `U+FFFD`.
+pub fn percent_decode(input: &str) -> Cow<'_, str> {
+    if !input.contains('%') {
+        return Cow::Borrowed(input);
+    }
+
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+
+    while idx < bytes.len() {
+        let escaped = match bytes[idx] {
+            b'%' => bytes.get(idx + 1..idx + 3).and_then(decode_hex_pair),
+            _ => None,
+        };
+
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                idx += 3;
+            }
+            None => {
+                decoded.push(bytes[idx]);
+                idx += 1;
+            }
+        }
+    }
+
+    Cow::Owned(String::from_utf8_lossy(&decoded).into_owned())
+}
+
+/// Decodes the two hex digits following a `%`, `None` unless both are hex digits.
+pub(crate) fn decode_hex_pair(hex: &[u8]) -> Option<u8> {
+    match hex {
+        [high, low] => {
+            let high = char::from(*high).to_digit(16)?;
+            let low = char::from(*low).to_digit(16)?;
+
+            u8::try_from(high * 16 + low).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Decodes a query string key or value, where `+` stands for a space
+/// (`application/x-www-form-urlencoded`).
+pub fn decode_query_component(input: &str) -> String {
+    if input.contains('+') {
+        percent_decode(&input.replace('+', " ")).into_owned()
+    } else {
+        percent_decode(input).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_hex_pair, decode_query_component, percent_decode};
+
+    #[test]
+    fn percent_decode_escapes() {
+        assert_eq!(percent_decode("hello%20world"), "hello world");
+        assert_eq!(percent_decode("%D0%BF%D1%80%D0%B8"), "при");
+        assert_eq!(percent_decode("a%2Fb%3d"), "a/b=");
+        assert_eq!(percent_decode("a+b"), "a+b");
+    }
+
+    #[test]
+    fn percent_decode_keeps_malformed_escapes() {
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%+5%-1"), "%+5%-1");
+        assert_eq!(percent_decode("%FF"), "\u{FFFD}");
+    }
+
+    #[test]
+    fn decode_hex_pair_takes_two_hex_digits() {
+        assert_eq!(decode_hex_pair(b"2f"), Some(0x2F));
+        assert_eq!(decode_hex_pair(b"FF"), Some(0xFF));
+        assert_eq!(decode_hex_pair(b"+5"), None);
+        assert_eq!(decode_hex_pair(b"4"), None);
+    }
+
+    #[test]
+    fn decode_query_component_plus_is_space() {
+        assert_eq!(decode_query_component("a+b%2Bc"), "a b+c");
+    }
+}
@@ -1,274 +1,683 @@
-use std::collections::HashMap;
-use std::io::{BufRead, Read, Result, Write};
+use std::io::{self, BufRead, ErrorKind, Read, Result, Write};
 use std::str::FromStr;
-use std::{io::BufReader, net::TcpStream, time::Duration};
+use std::sync::Arc;
 
+use crate::app_state::AppState;
+use crate::handler::{Handler, HandlerRequest};
+use crate::headers::Headers;
 use crate::http_request::HttpRequest;
-use crate::http_response::{HttpResponse, HttpStatus};
+use crate::http_response::{ChunkedBody, HttpResponse, HttpStatus};
 use crate::method_verb::HttpMethod;
-use crate::uri_params::{Query, Route};
+use crate::percent_decode::decode_query_component;
+use crate::router::{RouteMatch, Router};
+use crate::uri_params::Query;
+
+const CONTENT_LENGTH_HEADER: &str = "Content-Length";
+const TRANSFER_ENCODING_HEADER: &str = "Transfer-Encoding";
+const CHUNKED_ENCODING: &str = "chunked";
+const HTTP_1_0: &str = "HTTP/1.0";
+const MAX_REQUEST_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+const MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+/// Longest chunk size line, chunk extensions included.
+const MAX_CHUNK_SIZE_LINE: usize = 4096;
+
+pub type HttpRequestHandler = Arc<dyn Handler + Send + Sync>;
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    /// Client closed the connection (or went idle) before sending a request.
+    ConnectionClosed,
+    BadRequest,
+    /// Request method isn't one of the standard HTTP methods, or the body has a
+    /// transfer coding the server can't decode.
+    NotImplemented,
+    /// Request line is longer than allowed.
+    UriTooLong,
+    /// Too many headers or too many bytes of them.
+    HeadersTooLarge,
+    /// Body is longer than allowed.
+    PayloadTooLarge,
+    /// Client didn't send the headers or the body in time.
+    Timeout,
+    /// Request isn't HTTP/1.x.
+    VersionNotSupported,
+}
+
+/// Limits protecting the server from clients sending huge requests.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RequestLimits {
+    pub max_request_line: usize,
+    pub max_headers: usize,
+    /// Bytes of all header lines together.
+    pub max_header_size: usize,
+    pub max_body_size: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> RequestLimits {
+        RequestLimits {
+            max_request_line: MAX_REQUEST_LINE,
+            max_headers: MAX_HEADERS,
+            max_header_size: MAX_HEADER_SIZE,
+            max_body_size: MAX_BODY_SIZE,
+        }
+    }
+}
+
+/// Returns the number of body bytes written.
+pub fn write_response<W: Write>(writer: &mut W, response: HttpResponse) -> Result<usize> {
+    write_response_message(writer, response, true)
+}
+
+/// Writes the response to a `HEAD` request: same headers as for `GET`, without the body.
+pub fn write_head_response<W: Write>(writer: &mut W, response: HttpResponse) -> Result<usize> {
+    write_response_message(writer, response, false)
+}
+
+fn write_response_message<W: Write>(
+    writer: &mut W,
+    mut response: HttpResponse,
+    send_body: bool,
+) -> Result<usize> {
+    let has_content = could_have_content(&response.status);
+
+    if let Some(chunks) = streamed_body(&mut response) {
+        write_chunked_head(writer, &response)?;
+
+        if !send_body {
+            return writer.flush().map(|_| 0);
+        }
+
+        return write_chunks(writer, chunks);
+    }
+
+    let body = if has_content {
+        response.body.take().unwrap_or_default()
+    } else {
+        Vec::new()
+    };
 
-const CONTENT_LENGTH_HEADER: &str = "content-length:";
-const STREAM_READ_TIMEOUT: u64 = 5;
+    let mut response_message = response_head(&response);
 
-pub type HttpRequestHandler = fn(Route, Query, Option<String>) -> HttpResponse;
+    if has_content {
+        response_message = format!(
+            "{response_message}{CONTENT_LENGTH_HEADER}: {}\r\n",
+            body.len()
+        );
+    }
+
+    response_message = format!("{response_message}\r\n");
+
+    let mut response_message = response_message.into_bytes();
+    let body_length = if send_body { body.len() } else { 0 };
+
+    if send_body {
+        response_message.extend_from_slice(&body);
+    }
+
+    writer.write_all(&response_message)?;
+    writer.flush().map(|_| body_length)
+}
+
+/// Takes out the chunked body if the status allows a body, the response is then sent
+/// with `write_chunked_head` followed by `write_chunk` for every chunk.
+pub(crate) fn streamed_body(response: &mut HttpResponse) -> Option<ChunkedBody> {
+    response
+        .chunked_body
+        .take()
+        .filter(|_| could_have_content(&response.status))
+}
 
-pub fn return_response(mut stream: std::net::TcpStream, response: HttpResponse) -> () {
+/// Writes the status line and headers of a response which body follows in chunks.
+pub(crate) fn write_chunked_head<W: Write>(writer: &mut W, response: &HttpResponse) -> Result<()> {
+    let response_message = format!(
+        "{}{TRANSFER_ENCODING_HEADER}: {CHUNKED_ENCODING}\r\n\r\n",
+        response_head(response)
+    );
+
+    writer.write_all(response_message.as_bytes())
+}
+
+/// Status line and headers, without the framing headers and the empty line after them.
+fn response_head(response: &HttpResponse) -> String {
     let status_description = response.status.get_status_info();
+
     let mut response_message = format!(
-        "HTTP/1.1 {} {}\n\n",
+        "HTTP/1.1 {} {}\r\n",
         status_description.status_code, status_description.status_text
     );
 
-    if response.body.is_some() {
-        response_message = format!("{response_message}{}", response.body.unwrap());
+    for (name, value) in response.headers.iter() {
+        if name.eq_ignore_ascii_case(CONTENT_LENGTH_HEADER)
+            || name.eq_ignore_ascii_case(TRANSFER_ENCODING_HEADER)
+        {
+            continue;
+        }
+
+        // A line break in a value would let it add headers, or a body, of its own.
+        if !is_valid_header(name, value) {
+            log::warn!("dropping invalid response header {name:?}: {value:?}");
+            continue;
+        }
+
+        response_message = format!("{response_message}{name}: {value}\r\n");
     }
 
-    stream
-        .write_all(response_message.as_bytes())
-        .unwrap_or_else(|e| println!("{e}"));
+    response_message
 }
 
-pub fn parse_request(stream: std::net::TcpStream) -> Option<HttpRequest> {
-    stream
-        .set_read_timeout(Some(Duration::from_secs(STREAM_READ_TIMEOUT)))
-        .unwrap();
-
-    let mut reader = BufReader::new(&stream);
-    let mut start_line = String::new();
+fn write_chunks<W: Write>(writer: &mut W, chunks: ChunkedBody) -> Result<usize> {
+    let mut body_length = 0;
 
-    if reader.read_line(&mut start_line).is_err() {
-        return None;
+    for chunk in chunks {
+        write_chunk(writer, &chunk)?;
+        writer.flush()?;
+        body_length += chunk.len();
     }
 
-    let mut start_line_iter = start_line.split_whitespace();
-    let verb = start_line_iter.next().unwrap_or("");
-    let uri = start_line_iter.next().unwrap_or("");
-
-    let verb = HttpMethod::from_str(verb);
+    write_last_chunk(writer)?;
+    writer.flush().map(|_| body_length)
+}
 
-    if verb.is_err() || uri.is_empty() {
-        return None;
+pub(crate) fn write_chunk<W: Write>(writer: &mut W, chunk: &[u8]) -> Result<()> {
+    // Empty chunk would be taken as the end of the body.
+    if chunk.is_empty() {
+        return Ok(());
     }
 
-    let verb = verb.unwrap();
+    writer.write_all(format!("{:x}\r\n", chunk.len()).as_bytes())?;
+    writer.write_all(chunk)?;
+    writer.write_all(b"\r\n")
+}
 
-    if !could_have_body(&verb) {
-        return Some(HttpRequest {
-            method: verb,
-            uri: uri.to_string(),
-            body: None,
-        });
-    }
+pub(crate) fn write_last_chunk<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(b"0\r\n\r\n")
+}
 
-    let headers = read_headers(&mut reader);
+/// Whole request at once, the server reads the head and the body separately to give
+/// them different deadlines.
+#[cfg(test)]
+pub(crate) fn parse_request<R: BufRead>(
+    reader: &mut R,
+    limits: &RequestLimits,
+) -> std::result::Result<HttpRequest, ParseError> {
+    let mut request = parse_request_head(reader, limits)?;
+    request.body = read_request_body(reader, &request.headers, &mut request.trailers, limits)?;
+
+    Ok(request)
+}
 
-    if headers.is_err() {
-        return None;
+/// Parses the request line and the headers, the body is left for `read_request_body`.
+pub(crate) fn parse_request_head<R: BufRead>(
+    reader: &mut R,
+    limits: &RequestLimits,
+) -> std::result::Result<HttpRequest, ParseError> {
+    let mut start_line = String::new();
+
+    // Clients may send empty lines between pipelined requests.
+    while start_line.trim().is_empty() {
+        start_line.clear();
+
+        match read_line(reader, &mut start_line, limits.max_request_line) {
+            Ok(0) => return Err(ParseError::ConnectionClosed),
+            Ok(_) => (),
+            Err(LineError::TooLong) => return Err(ParseError::UriTooLong),
+            Err(LineError::Io(e)) if is_timeout(&e) => return Err(ParseError::Timeout),
+            Err(LineError::Io(_)) => return Err(ParseError::ConnectionClosed),
+        }
     }
 
-    let headers = headers.unwrap();
+    let mut start_line_iter = start_line.split_whitespace();
+    let verb = start_line_iter.next().unwrap_or("");
+    let uri = start_line_iter.next().unwrap_or("");
+    let version = start_line_iter.next().unwrap_or(HTTP_1_0);
 
-    let body_length = get_content_length_header(&headers).unwrap_or(0);
+    if uri.is_empty() {
+        return Err(ParseError::BadRequest);
+    }
 
-    if body_length == 0 {
-        return Some(HttpRequest {
-            method: verb,
-            uri: uri.to_string(),
-            body: None,
-        });
+    match version
+        .strip_prefix("HTTP/")
+        .and_then(|number| number.split_once('.'))
+    {
+        Some((major, minor)) if is_version_digit(major) && is_version_digit(minor) => {
+            if major != "1" {
+                return Err(ParseError::VersionNotSupported);
+            }
+        }
+        _ => return Err(ParseError::BadRequest),
     }
 
-    let body = read_body(&mut reader, body_length);
+    let verb = HttpMethod::from_str(verb).map_err(|_| ParseError::NotImplemented)?;
 
-    if body.is_err() {
-        return None;
-    }
-    let body = body.unwrap();
+    let headers = read_headers(reader, limits)?;
 
-    return Some(HttpRequest {
+    Ok(HttpRequest {
         method: verb,
         uri: uri.to_string(),
-        body: Some(body),
-    });
+        version: version.to_string(),
+        headers,
+        body: None,
+        trailers: Headers::new(),
+        client_certificate: None,
+    })
 }
 
 pub fn parse_query(uri: &str) -> Query {
     let query_start = uri.find('?');
 
     if query_start.is_none() {
-        return Query(HashMap::new());
+        return Query(Vec::new());
     }
 
     let queries_pairs = uri.get(query_start.unwrap() + 1..).unwrap();
-    let mut queries = HashMap::new();
+    let mut queries = Vec::new();
 
-    for pair in queries_pairs.split('&') {
-        let mut key_value = pair.split('=');
-        let key = key_value.next().unwrap_or("");
-        let value = key_value.next().unwrap_or("");
+    for pair in queries_pairs.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
 
-        if key != "" && value != "" {
-            queries.insert(key.to_string(), value.to_string());
-        }
+        queries.push((decode_query_component(key), decode_query_component(value)));
     }
 
     Query(queries)
 }
 
 pub fn parse_route<'a>(
-    endpoints: &'a HashMap<String, HttpRequestHandler>,
+    router: &'a Router<HttpRequestHandler>,
     request_uri: &str,
-) -> (Option<&'a HttpRequestHandler>, Route) {
-    let query_start = request_uri.find('?');
-
-    let route = match query_start {
-        Some(idx) => request_uri.get(0..idx).unwrap(),
+) -> Option<RouteMatch<'a, HttpRequestHandler>> {
+    let path = match request_uri.find('?') {
+        Some(idx) => &request_uri[..idx],
         None => request_uri,
     };
 
-    if let Some(handler) = endpoints.get(route) {
-        return (Some(handler), Route(HashMap::new()));
+    router.find(path)
+}
+
+/// Handler registered for the route matching the request uri.
+pub struct MatchedHandler {
+    pub handler: HttpRequestHandler,
+    /// Template of the matched route, reported in the access log.
+    pub route: String,
+    pub request: HandlerRequest,
+}
+
+/// Handler of the route found with `parse_route` with the request it has to answer.
+pub fn match_handler(
+    route_match: RouteMatch<'_, HttpRequestHandler>,
+    request: HttpRequest,
+    state: &AppState,
+) -> MatchedHandler {
+    let query = parse_query(&request.uri);
+
+    MatchedHandler {
+        handler: Arc::clone(route_match.value),
+        route: route_match.route.to_string(),
+        request: HandlerRequest {
+            route: route_match.params,
+            query,
+            headers: request.headers,
+            state: state.clone(),
+            body: request.body,
+            client_certificate: request.client_certificate,
+        },
     }
+}
 
-    let request_parts: Vec<_> = route.split('/').collect();
-    let mut params: HashMap<String, String> = HashMap::new();
-    let mut handler: Option<&HttpRequestHandler> = None;
+fn read_body<R: BufRead>(reader: &mut R, body_length: usize) -> Result<Vec<u8>> {
+    let mut buffer = vec![0; body_length];
+    reader.read_exact(&mut buffer)?;
 
-    for key in endpoints.keys() {
-        let endpoint_parts: Vec<_> = key.split('/').collect();
+    Ok(buffer)
+}
 
-        if request_parts.len() != endpoint_parts.len() {
-            continue;
+/// How the end of a request body is found.
+pub(crate) enum BodyFraming {
+    Length(usize),
+    Chunked,
+}
+
+/// Reads the body announced by the headers parsed with `parse_request_head`, trailer
+/// fields of a chunked body go to `trailers`.
+pub(crate) fn read_request_body<R: BufRead>(
+    reader: &mut R,
+    headers: &Headers,
+    trailers: &mut Headers,
+    limits: &RequestLimits,
+) -> std::result::Result<Option<Vec<u8>>, ParseError> {
+    match body_framing(headers, limits)? {
+        BodyFraming::Length(0) => Ok(None),
+        BodyFraming::Length(body_length) => read_body(reader, body_length)
+            .map(Some)
+            .map_err(|e| io_parse_error(&e)),
+        BodyFraming::Chunked => {
+            let mut decoder = ChunkDecoder::default();
+
+            loop {
+                if let Some(fields) = decoder.decode(reader, limits)? {
+                    *trailers = fields;
+                    return Ok(Some(decoder.body).filter(|body| !body.is_empty()));
+                }
+            }
         }
+    }
+}
 
-        handler = endpoints.get(key);
+/// Tells how the body is framed. Framing a proxy in front of the server could read
+/// differently (RFC 9112 section 6.3) is rejected: `Transfer-Encoding` together with
+/// `Content-Length`, more than one length or a length which isn't just digits.
+/// Transfer codings other than `chunked` aren't supported (501).
+pub(crate) fn body_framing(
+    headers: &Headers,
+    limits: &RequestLimits,
+) -> std::result::Result<BodyFraming, ParseError> {
+    let lengths = headers.get_all(CONTENT_LENGTH_HEADER);
+    let encodings = headers.get_all(TRANSFER_ENCODING_HEADER);
+
+    if !encodings.is_empty() {
+        if !lengths.is_empty() {
+            return Err(ParseError::BadRequest);
+        }
 
-        for (i, &part) in endpoint_parts.iter().enumerate() {
-            if part == request_parts[i] {
-                continue;
-            }
+        let codings: Vec<&str> = encodings
+            .iter()
+            .flat_map(|encoding| encoding.split(','))
+            .map(str::trim)
+            .collect();
+
+        // Other codings would have to be decoded, the body would reach the handlers
+        // still encoded otherwise (RFC 9112 section 6.1).
+        if codings
+            .iter()
+            .any(|coding| !coding.is_empty() && !coding.eq_ignore_ascii_case(CHUNKED_ENCODING))
+        {
+            return Err(ParseError::NotImplemented);
+        }
 
-            if part.len() > 0
-                && part.chars().nth(0).unwrap() == '{'
-                && part.chars().nth(part.len() - 1).unwrap() == '}'
-            {
-                params.insert(
-                    part.get(1..part.len() - 1).unwrap().to_string(),
-                    request_parts[i].to_string(),
-                );
-            } else {
-                params.clear();
-                handler = None;
-                break;
-            }
+        // Chunked has to be there, and only once.
+        if codings.len() != 1 || codings[0].is_empty() {
+            return Err(ParseError::BadRequest);
         }
+
+        return Ok(BodyFraming::Chunked);
     }
 
-    (handler, Route(params))
-}
+    let body_length = match lengths.as_slice() {
+        [] => 0,
+        [length] if is_digits(length) => length
+            .parse::<usize>()
+            .map_err(|_| ParseError::BadRequest)?,
+        _ => return Err(ParseError::BadRequest),
+    };
 
-pub fn handle_request(
-    request: HttpRequest,
-    endpoints_map: &HashMap<String, HttpRequestHandler>,
-    stream: TcpStream,
-) {
-    let (handler, route) = parse_route(&endpoints_map, &request.uri);
-
-    if handler.is_none() {
-        return_response(
-            stream,
-            HttpResponse {
-                status: HttpStatus::NotFound,
-                body: None,
-            },
-        );
-        return;
+    // Checked before the buffer is allocated, the length comes from the client.
+    if body_length > limits.max_body_size {
+        return Err(ParseError::PayloadTooLarge);
     }
 
-    let handler = handler.unwrap();
-    let query = parse_query(&request.uri);
-    let response = handler(route, query, request.body);
+    Ok(BodyFraming::Length(body_length))
+}
 
-    return_response(stream, response);
+/// Decodes a `Transfer-Encoding: chunked` body a step at a time, the decoded data goes
+/// to `body`. A step which fails leaves the decoder as it was, so it can be tried again
+/// once more bytes arrive. The size lines and line ends count toward `max_body_size`
+/// together with the data.
+#[derive(Default)]
+pub(crate) struct ChunkDecoder {
+    pub(crate) body: Vec<u8>,
+    step: ChunkStep,
+    framing: usize,
 }
 
-fn read_body(reader: &mut BufReader<&TcpStream>, body_length: usize) -> Result<String> {
-    let mut buffer = vec![0; body_length];
-    let mut bytes_num = 0;
+#[derive(Default)]
+enum ChunkStep {
+    #[default]
+    Size,
+    /// Bytes of the current chunk which are still to come.
+    Data(usize),
+    DataEnd,
+    Trailers,
+}
 
-    loop {
-        bytes_num += reader.read(&mut buffer)?;
+impl ChunkDecoder {
+    /// Decodes the next part of the body, a size line, data or a line end. Returns the
+    /// trailer fields once the last chunk is read.
+    pub(crate) fn decode<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        limits: &RequestLimits,
+    ) -> std::result::Result<Option<Headers>, ParseError> {
+        match self.step {
+            ChunkStep::Size => {
+                let (chunk_size, read) = read_chunk_size(reader)?;
+                let framing = self.framing + read;
+
+                if chunk_size == 0 {
+                    self.framing = framing;
+                    self.step = ChunkStep::Trailers;
+                } else if chunk_size
+                    > limits
+                        .max_body_size
+                        .saturating_sub(self.body.len() + framing)
+                {
+                    return Err(ParseError::PayloadTooLarge);
+                } else {
+                    self.framing = framing;
+                    self.step = ChunkStep::Data(chunk_size);
+                }
+
+                Ok(None)
+            }
+            ChunkStep::Data(remaining) => {
+                let available = reader.fill_buf().map_err(|e| io_parse_error(&e))?;
 
-        if bytes_num == body_length {
-            break;
+                if available.is_empty() {
+                    return Err(ParseError::BadRequest);
+                }
+
+                let read = available.len().min(remaining);
+                self.body.extend_from_slice(&available[..read]);
+                reader.consume(read);
+
+                self.step = if read == remaining {
+                    ChunkStep::DataEnd
+                } else {
+                    ChunkStep::Data(remaining - read)
+                };
+
+                Ok(None)
+            }
+            ChunkStep::DataEnd => {
+                let mut line = String::new();
+                let read = read_line(reader, &mut line, MAX_CHUNK_SIZE_LINE)?;
+
+                if line != "\r\n" && line != "\n" {
+                    return Err(ParseError::BadRequest);
+                }
+
+                self.framing += read;
+                self.step = ChunkStep::Size;
+
+                Ok(None)
+            }
+            ChunkStep::Trailers => read_headers(reader, limits).map(Some),
         }
     }
-
-    Ok(String::from_utf8(buffer).unwrap())
 }
 
-fn get_content_length_header(headers: &String) -> Option<usize> {
-    let start_idx = headers.to_lowercase().find(CONTENT_LENGTH_HEADER);
+/// Reads the size line of a chunk, returns the size and the bytes the line took.
+fn read_chunk_size<R: BufRead>(reader: &mut R) -> std::result::Result<(usize, usize), ParseError> {
+    let mut line = String::new();
+    let read = read_line(reader, &mut line, MAX_CHUNK_SIZE_LINE)?;
 
-    if start_idx.is_none() {
-        return None;
+    // A line without its end may be cut short, the size could go on.
+    if !line.ends_with('\n') {
+        return Err(ParseError::BadRequest);
     }
 
-    let start_idx = start_idx.unwrap();
-    let end_idx = headers.get(start_idx..).unwrap().find("\n").unwrap() + start_idx;
-
-    let content_length_header = headers.get(start_idx..end_idx).unwrap();
-    let content_length = content_length_header.split(':').into_iter().last().unwrap();
+    // Chunk extensions are allowed after `;` and ignored.
+    let chunk_size = line.split(';').next().unwrap_or("").trim();
 
-    match content_length.trim().parse::<usize>() {
-        Ok(length) => Some(length),
-        Err(_) => None,
+    if chunk_size.is_empty() || !chunk_size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(ParseError::BadRequest);
     }
+
+    let chunk_size = usize::from_str_radix(chunk_size, 16).map_err(|_| ParseError::BadRequest)?;
+
+    Ok((chunk_size, read))
 }
 
-fn read_headers(reader: &mut BufReader<&TcpStream>) -> Result<String> {
-    let mut buffer = String::new();
+fn read_headers<R: BufRead>(
+    reader: &mut R,
+    limits: &RequestLimits,
+) -> std::result::Result<Headers, ParseError> {
+    let mut headers = Headers::new();
     let mut current_string = String::new();
+    let mut headers_size = 0;
+    let mut headers_count = 0;
 
     loop {
         current_string.clear();
-        reader.read_line(&mut current_string)?;
 
-        if current_string == "\r\n" {
+        // The empty line ending the headers doesn't count toward the limit.
+        let remaining = limits.max_header_size - headers_size + 2;
+        let read = match read_line(reader, &mut current_string, remaining) {
+            Ok(read) if current_string.ends_with('\n') => read,
+            Ok(_) => return Err(ParseError::BadRequest),
+            Err(LineError::TooLong) => return Err(ParseError::HeadersTooLarge),
+            Err(LineError::Io(e)) => return Err(io_parse_error(&e)),
+        };
+
+        let line = current_string.trim_end_matches(['\r', '\n']);
+
+        if line.is_empty() {
             break;
         }
 
-        buffer = format!("{buffer}{current_string}");
+        headers_size += read;
+        headers_count += 1;
+
+        if headers_size > limits.max_header_size || headers_count > limits.max_headers {
+            return Err(ParseError::HeadersTooLarge);
+        }
+
+        let (name, value) = line.split_once(':').ok_or(ParseError::BadRequest)?;
+
+        // Whitespace before the colon or a folded line could make a proxy see another
+        // header than the server does.
+        if name.is_empty() || !name.bytes().all(is_token_char) {
+            return Err(ParseError::BadRequest);
+        }
+
+        headers.append(name, value.trim());
     }
 
-    Ok(buffer)
+    Ok(headers)
 }
 
-fn could_have_body(method: &HttpMethod) -> bool {
-    match method {
-        HttpMethod::Get | HttpMethod::Delete => false,
-        HttpMethod::Post | HttpMethod::Put => true,
+enum LineError {
+    /// No line end within the limit.
+    TooLong,
+    Io(io::Error),
+}
+
+impl From<LineError> for ParseError {
+    fn from(error: LineError) -> ParseError {
+        match error {
+            LineError::TooLong => ParseError::BadRequest,
+            LineError::Io(e) => io_parse_error(&e),
+        }
     }
 }
 
+/// `read_line` which stops after `limit` bytes instead of reading a line of any length
+/// into memory.
+fn read_line<R: BufRead>(
+    reader: &mut R,
+    line: &mut String,
+    limit: usize,
+) -> std::result::Result<usize, LineError> {
+    let read = reader
+        .take(limit as u64 + 1)
+        .read_line(line)
+        .map_err(LineError::Io)?;
+
+    if read > limit {
+        return Err(LineError::TooLong);
+    }
+
+    Ok(read)
+}
+
+fn io_parse_error(error: &io::Error) -> ParseError {
+    if is_timeout(error) {
+        ParseError::Timeout
+    } else {
+        ParseError::BadRequest
+    }
+}
+
+/// Read timeouts are reported as `WouldBlock` on Unix and `TimedOut` on Windows.
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Major and minor versions are a single digit each (RFC 9112 section 2.3).
+fn is_version_digit(number: &str) -> bool {
+    number.len() == 1 && number.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// Names have to be tokens (RFC 9110), values can't contain CR, LF or NUL.
+fn is_valid_header(name: &str, value: &str) -> bool {
+    !name.is_empty()
+        && name.bytes().all(is_token_char)
+        && !value
+            .bytes()
+            .any(|byte| matches!(byte, b'\r' | b'\n' | b'\0'))
+}
+
+fn is_token_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// `parse` alone would accept a sign, e.g. `+5`.
+fn is_digits(value: &str) -> bool {
+    !value.is_empty() && value.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// 1xx, 204 and 304 responses must be sent without a body and without
+/// `Content-Length`. A client doesn't read a body after them, it would be taken as the
+/// start of the next response.
+fn could_have_content(status: &HttpStatus) -> bool {
+    let code = status.code();
+
+    code >= 200 && code != 204 && code != 304
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::sync::Arc;
 
     use crate::{
-        http_response::HttpResponse,
+        http_response::{HttpResponse, HttpStatus},
         request_parser::{parse_route, HttpRequestHandler},
+        router::Router,
         uri_params::{Query, Route},
     };
 
-    use super::parse_query;
+    use super::{
+        parse_query, parse_request, write_head_response, write_response, ChunkDecoder, ParseError,
+        RequestLimits,
+    };
 
     #[test]
     fn parse_query_params_empty_list() {
         let query = parse_query("http://localhost:42069/");
 
-        assert_eq!(query, Query(HashMap::new()));
+        assert_eq!(query, Query(Vec::new()));
     }
 
     #[test]
@@ -279,67 +688,464 @@ mod tests {
 
         assert_eq!(
             query,
-            Query(HashMap::from([
+            Query(vec![
                 ("a_param".to_string(), "123".to_string()),
                 ("b_param".to_string(), "str".to_string()),
                 ("c_param".to_string(), "[123,abc,123]".to_string())
-            ]))
+            ])
         );
     }
 
     #[test]
-    fn parse_query_params_incorrect_query() {
-        let query = parse_query("/some/path?a_param=123&b_param=");
+    fn parse_query_params_keeps_empty_values_and_flags() {
+        let query = parse_query("/some/path?a_param=123&b_param=&debug&&c=x=y");
 
         assert_eq!(
             query,
-            Query(HashMap::from([("a_param".to_string(), "123".to_string()),]))
+            Query(vec![
+                ("a_param".to_string(), "123".to_string()),
+                ("b_param".to_string(), "".to_string()),
+                ("debug".to_string(), "".to_string()),
+                ("c".to_string(), "x=y".to_string()),
+            ])
         );
+        assert!(query.contains("debug"));
+        assert_eq!(query.get("b_param"), Some(""));
     }
 
     #[test]
-    fn parse_route_params_empty_list() {
-        let expected_handler: HttpRequestHandler = |_, _, _| HttpResponse::ok(None);
-        let handler_1: HttpRequestHandler = |_, _, _| HttpResponse::ok(None);
-        let handler_2: HttpRequestHandler = |_, _, _| HttpResponse::ok(None);
+    fn parse_query_params_decodes_and_keeps_repeated_keys() {
+        let query = parse_query("/search?q=hello+world%21&tag=a&tag=b%20c&na%6De=x");
+
+        assert_eq!(query.get("q"), Some("hello world!"));
+        assert_eq!(query.get("tag"), Some("a"));
+        assert_eq!(query.get_all("tag"), vec!["a", "b c"]);
+        assert_eq!(query.get("name"), Some("x"));
+        assert!(query.get_all("missing").is_empty());
+    }
+
+    #[test]
+    fn parse_pipelined_requests() {
+        let mut reader = "GET /a HTTP/1.1\r\nHost: x\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\r\nGET /c HTTP/1.0\r\n\r\n"
+            .as_bytes();
+
+        let first = parse_request(&mut reader, &RequestLimits::default()).unwrap();
+        assert_eq!(first.uri, "/a");
+        assert_eq!(first.headers.get("host"), Some("x"));
+        assert_eq!(first.body, None);
 
-        let handlers = &HashMap::from([
-            ("/some/very/very/very/long/path".to_string(), handler_1),
-            ("/some/very/very/long/path".to_string(), expected_handler),
-            ("/some/very/long/path".to_string(), handler_2),
-        ]);
+        let second = parse_request(&mut reader, &RequestLimits::default()).unwrap();
+        assert_eq!(second.uri, "/b");
+        assert_eq!(second.body, Some(b"hello".to_vec()));
 
-        let (handler, route) =
-            parse_route(handlers, "/some/very/very/long/path?a_param=123&b_param=");
+        let third = parse_request(&mut reader, &RequestLimits::default()).unwrap();
+        assert_eq!(third.uri, "/c");
+        assert_eq!(third.version, "HTTP/1.0");
 
-        assert_eq!(route, Route(HashMap::new()));
-        assert!(&handler.is_some());
+        assert_eq!(
+            parse_request(&mut reader, &RequestLimits::default()).unwrap_err(),
+            ParseError::ConnectionClosed
+        );
+    }
+
+    #[test]
+    fn parse_request_invalid_content_length() {
+        let mut reader = "POST / HTTP/1.1\r\nContent-Length: abc\r\n\r\n".as_bytes();
+
+        assert_eq!(
+            parse_request(&mut reader, &RequestLimits::default()).unwrap_err(),
+            ParseError::BadRequest
+        );
+    }
+
+    #[test]
+    fn parse_request_rejects_ambiguous_framing() {
+        let requests = [
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n0\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\nhello!",
+            "POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 5\r\n\r\nhello",
+            "POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\nhello",
+            "POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello",
+            "POST / HTTP/1.1\r\nContent-Length: -5\r\n\r\nhello",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+5\r\nhello\r\n0\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0x5\r\nhello\r\n0\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length : 5\r\n\r\nhello",
+            "POST / HTTP/1.1\r\nX-Foo: a\r\n Content-Length: 5\r\n\r\nhello",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked, chunked\r\n\r\n0\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: \r\n\r\n0\r\n\r\n",
+        ];
+
+        for request in requests {
+            assert_eq!(
+                parse_request(&mut request.as_bytes(), &RequestLimits::default()).unwrap_err(),
+                ParseError::BadRequest,
+                "{request:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_request_rejects_unsupported_transfer_codings() {
+        let requests = [
+            "POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n0\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: gzip\r\n\r\n0\r\n\r\n",
+        ];
+
+        for request in requests {
+            assert_eq!(
+                parse_request(&mut request.as_bytes(), &RequestLimits::default()).unwrap_err(),
+                ParseError::NotImplemented,
+                "{request:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_request_accepts_only_http_1() {
+        let parse =
+            |request: &str| parse_request(&mut request.as_bytes(), &RequestLimits::default());
+
+        assert_eq!(parse("GET / HTTP/1.0\r\n\r\n").unwrap().version, "HTTP/1.0");
+        assert_eq!(parse("GET / HTTP/1.2\r\n\r\n").unwrap().version, "HTTP/1.2");
+        assert_eq!(
+            parse("GET / HTTP/2.0\r\n\r\n").unwrap_err(),
+            ParseError::VersionNotSupported
+        );
+
+        for version in ["HTTP/1.10", "HTTP/1", "HTTP/x.1", "HTTPS/1.1"] {
+            assert_eq!(
+                parse(&format!("GET / {version}\r\n\r\n")).unwrap_err(),
+                ParseError::BadRequest,
+                "{version}"
+            );
+        }
+    }
+
+    #[test]
+    fn parse_binary_body() {
+        let mut reader =
+            &b"POST /upload HTTP/1.1\r\nContent-Length: 4\r\n\r\n\x89PNGPOST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n\xff\x00\r\n0\r\n\r\n"[..];
+
+        let request = parse_request(&mut reader, &RequestLimits::default()).unwrap();
+        assert_eq!(request.body, Some(b"\x89PNG".to_vec()));
+
+        let request = parse_request(&mut reader, &RequestLimits::default()).unwrap();
+        assert_eq!(request.body, Some(vec![0xff, 0x00]));
+    }
+
+    #[test]
+    fn parse_chunked_request_with_trailers() {
+        let mut reader = "POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;ext=1\r\nhello\r\n7\r\n, world\r\n0\r\nX-Checksum: abc\r\n\r\nGET / HTTP/1.1\r\n\r\n"
+            .as_bytes();
+
+        let request = parse_request(&mut reader, &RequestLimits::default()).unwrap();
+
+        assert_eq!(request.body, Some(b"hello, world".to_vec()));
+        assert_eq!(request.trailers.get("x-checksum"), Some("abc"));
+        assert_eq!(request.headers.get("x-checksum"), None);
+        assert_eq!(
+            parse_request(&mut reader, &RequestLimits::default())
+                .unwrap()
+                .uri,
+            "/"
+        );
+    }
+
+    #[test]
+    fn parse_chunked_request_malformed_size() {
+        let mut reader =
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\nhello\r\n0\r\n\r\n"
+                .as_bytes();
+
+        assert_eq!(
+            parse_request(&mut reader, &RequestLimits::default()).unwrap_err(),
+            ParseError::BadRequest
+        );
+    }
+
+    #[test]
+    fn chunk_decoder_counts_framing_toward_the_body_limit() {
+        let limits = RequestLimits {
+            max_body_size: 16,
+            ..RequestLimits::default()
+        };
+        let mut reader = "1;padding\r\na\r\n1\r\nb\r\n".as_bytes();
+        let mut decoder = ChunkDecoder::default();
+
+        assert_eq!(decoder.decode(&mut reader, &limits), Ok(None));
+        assert_eq!(decoder.decode(&mut reader, &limits), Ok(None));
+        assert_eq!(decoder.decode(&mut reader, &limits), Ok(None));
+        assert_eq!(
+            decoder.decode(&mut reader, &limits),
+            Err(ParseError::PayloadTooLarge)
+        );
+        assert_eq!(decoder.body, b"a");
+    }
+
+    #[test]
+    fn chunk_decoder_keeps_its_state_when_a_line_is_cut_short() {
+        let limits = RequestLimits::default();
+        let mut decoder = ChunkDecoder::default();
+
+        assert_eq!(
+            decoder.decode(&mut "1".as_bytes(), &limits),
+            Err(ParseError::BadRequest)
+        );
+        assert_eq!(decoder.decode(&mut "10\r\n".as_bytes(), &limits), Ok(None));
+        assert_eq!(decoder.decode(&mut "abc".as_bytes(), &limits), Ok(None));
+        assert_eq!(
+            decoder.decode(&mut "defghijklmnopqrstu".as_bytes(), &limits),
+            Ok(None)
+        );
+        assert_eq!(decoder.body, b"abcdefghijklmnop");
+    }
+
+    #[test]
+    fn write_chunked_response() {
+        let response = HttpResponse::chunked(
+            HttpStatus::Ok,
+            vec!["hello".to_string(), String::new(), ", world".to_string()],
+        )
+        .header("Content-Length", "100");
+        let mut output = Vec::new();
+
+        write_response(&mut output, response).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn write_response_with_headers_and_body() {
+        let response = HttpResponse::new(HttpStatus::NotFound, Some("{}".to_string()))
+            .header("X-Foo", "bar")
+            .header("Content-Length", "100");
+        let mut output = Vec::new();
+
+        write_response(&mut output, response).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 404 Not Found\r\nX-Foo: bar\r\nContent-Length: 2\r\n\r\n{}"
+        );
+    }
+
+    #[test]
+    fn write_binary_response() {
+        let response = HttpResponse::bytes(HttpStatus::Ok, [0x89, b'P', 0xff]);
+        let mut output = Vec::new();
+
+        assert_eq!(write_response(&mut output, response).unwrap(), 3);
+        assert_eq!(
+            output,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n\x89P\xff"
+        );
+
+        let response = HttpResponse::chunked(HttpStatus::Ok, vec![vec![0xff, 0x00]]);
+        let mut output = Vec::new();
+
+        write_response(&mut output, response).unwrap();
+
+        assert_eq!(
+            output,
+            b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n\xff\x00\r\n0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn write_response_drops_invalid_headers() {
+        let response = HttpResponse::ok(None)
+            .header("Location", "/next\r\nSet-Cookie: session=evil")
+            .header("X Forwarded", "1")
+            .header("X-Nul", "a\0b")
+            .header("X-Good", "ok");
+        let mut output = Vec::new();
+
+        write_response(&mut output, response).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\nX-Good: ok\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn write_response_without_body() {
+        let mut output = Vec::new();
+
+        write_response(&mut output, HttpResponse::ok(None)).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn parse_request_unknown_method() {
+        let mut reader = "BREW /pot HTTP/1.1\r\n\r\n".as_bytes();
+
+        assert_eq!(
+            parse_request(&mut reader, &RequestLimits::default()).unwrap_err(),
+            ParseError::NotImplemented
+        );
+    }
+
+    #[test]
+    fn parse_request_over_limits() {
+        let limits = RequestLimits {
+            max_request_line: 20,
+            max_headers: 2,
+            max_header_size: 40,
+            max_body_size: 5,
+        };
+        let parse = |request: &str| parse_request(&mut request.as_bytes(), &limits);
+
+        assert_eq!(
+            parse("GET /a/very/long/path HTTP/1.1\r\n\r\n").unwrap_err(),
+            ParseError::UriTooLong
+        );
+        assert_eq!(
+            parse("GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n").unwrap_err(),
+            ParseError::HeadersTooLarge
+        );
+        assert_eq!(
+            parse("GET / HTTP/1.1\r\nCookie: 0123456789012345678901234567890123\r\n\r\n")
+                .unwrap_err(),
+            ParseError::HeadersTooLarge
+        );
+        assert_eq!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 99999999999\r\n\r\n").unwrap_err(),
+            ParseError::PayloadTooLarge
+        );
+        assert_eq!(
+            parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n3\r\ndef\r\n0\r\n\r\n")
+                .unwrap_err(),
+            ParseError::PayloadTooLarge
+        );
+        assert_eq!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello")
+                .unwrap()
+                .body
+                .as_deref(),
+            Some("hello".as_bytes())
+        );
+    }
+
+    #[test]
+    fn parse_request_headers_filling_the_limit() {
+        let limits = RequestLimits {
+            max_header_size: 40,
+            ..RequestLimits::default()
+        };
+        let parse = |headers: &str| {
+            parse_request(
+                &mut format!("GET / HTTP/1.1\r\n{headers}\r\n").as_bytes(),
+                &limits,
+            )
+        };
+
+        // Header lines of 40 bytes, their line ends included.
+        assert!(parse(&format!("X: {}\r\n", "a".repeat(35))).is_ok());
+        assert!(parse(&format!("A: 1\r\nB: {}\r\n", "b".repeat(29))).is_ok());
+
+        assert_eq!(
+            parse(&format!("X: {}\r\n", "a".repeat(36))).unwrap_err(),
+            ParseError::HeadersTooLarge
+        );
+        assert_eq!(
+            parse(&format!("A: 1\r\nB: {}\r\n", "b".repeat(30))).unwrap_err(),
+            ParseError::HeadersTooLarge
+        );
+    }
+
+    #[test]
+    fn write_head_response_keeps_content_length() {
+        let mut output = Vec::new();
+
+        write_head_response(&mut output, HttpResponse::ok(Some("hello".to_string()))).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn write_response_no_content() {
+        let mut output = Vec::new();
+
+        write_response(
+            &mut output,
+            HttpResponse::new(HttpStatus::NoContent, Some("ignored".to_string())),
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 204 No Content\r\n\r\n"
+        );
+
+        let mut output = Vec::new();
+
+        write_response(
+            &mut output,
+            HttpResponse::new(HttpStatus::NotModified, Some("ignored".to_string()))
+                .header("ETag", "\"v1\""),
+        )
+        .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn parse_route_params_empty_list() {
+        let expected_handler: HttpRequestHandler = Arc::new(|_| HttpResponse::ok(None));
+        let handler_1: HttpRequestHandler = Arc::new(|_| HttpResponse::ok(None));
+        let handler_2: HttpRequestHandler = Arc::new(|_| HttpResponse::ok(None));
+
+        let mut handlers = Router::new();
+        handlers
+            .insert("/some/very/very/very/long/path", handler_1)
+            .unwrap();
+        handlers
+            .insert("/some/very/very/long/path", expected_handler)
+            .unwrap();
+        handlers.insert("/some/very/long/path", handler_2).unwrap();
+
+        let route_match =
+            parse_route(&handlers, "/some/very/very/long/path?a_param=123&b_param=").unwrap();
+
+        assert_eq!(route_match.params, Route(HashMap::new()));
     }
 
     #[test]
     fn parse_route_params_not_empty_list() {
-        let expected_handler: HttpRequestHandler = |_, _, _| HttpResponse::ok(None);
-        let handler_1: HttpRequestHandler = |_, _, _| HttpResponse::ok(None);
-        let handler_2: HttpRequestHandler = |_, _, _| HttpResponse::ok(None);
-
-        let handlers = &HashMap::from([
-            (
-                "/some/very/{param_1}/very/{param_2}/path".to_string(),
-                handler_1,
-            ),
-            ("/some/very/long/path".to_string(), handler_2),
-            (
-                "/some/{param_1}/very/{param_2}/{param_3}".to_string(),
-                expected_handler,
-            ),
-        ]);
-
-        let (handler, route) =
-            parse_route(handlers, "/some/very/very/long/path?a_param=123&b_param=");
-
-        let Route(actual_route_map) = route;
+        let expected_handler: HttpRequestHandler = Arc::new(|_| HttpResponse::ok(None));
+        let handler_1: HttpRequestHandler = Arc::new(|_| HttpResponse::ok(None));
+        let handler_2: HttpRequestHandler = Arc::new(|_| HttpResponse::ok(None));
+
+        let mut handlers = Router::new();
+        handlers
+            .insert("/some/very/{param_1}/very/{param_2}/path", handler_1)
+            .unwrap();
+        handlers.insert("/some/very/long/path", handler_2).unwrap();
+        handlers
+            .insert("/some/{param_1}/very/{param_2}/{param_3}", expected_handler)
+            .unwrap();
+
+        let route_match =
+            parse_route(&handlers, "/some/very/very/long/path?a_param=123&b_param=").unwrap();
+
+        let Route(actual_route_map) = route_match.params;
         let mut actual_route_vec: Vec<_> = actual_route_map.iter().collect();
-        actual_route_vec.sort_by(|(a, _), (b, _)| a.cmp(b));
+        actual_route_vec.sort_by_key(|(a, _)| *a);
 
         assert_eq!(
             actual_route_vec,
@@ -349,6 +1155,5 @@ mod tests {
                 (&"param_3".to_string(), &"path".to_string()),
             )
         );
-        assert!(&handler.is_some());
     }
 }
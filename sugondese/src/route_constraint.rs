@@ -0,0 +1,105 @@
+use regex::Regex;
+
+/// Constraint of a `{name:constraint}` route segment. Known type names are checked by
+/// parsing the value, anything else is taken as a regular expression the whole segment
+/// has to match.
+#[derive(Clone, Debug)]
+pub enum RouteConstraint {
+    Int { min: i128, max: i128 },
+    Float,
+    Bool,
+    Uuid,
+    Pattern(Regex),
+}
+
+impl RouteConstraint {
+    pub fn parse(source: &str) -> Result<RouteConstraint, String> {
+        let constraint = match source {
+            "i8" => int_constraint(i8::MIN, i8::MAX),
+            "i16" => int_constraint(i16::MIN, i16::MAX),
+            "i32" => int_constraint(i32::MIN, i32::MAX),
+            "i64" => int_constraint(i64::MIN, i64::MAX),
+            "u8" => int_constraint(u8::MIN, u8::MAX),
+            "u16" => int_constraint(u16::MIN, u16::MAX),
+            "u32" => int_constraint(u32::MIN, u32::MAX),
+            "u64" => int_constraint(u64::MIN, u64::MAX),
+            "f32" | "f64" => RouteConstraint::Float,
+            "bool" => RouteConstraint::Bool,
+            "uuid" => RouteConstraint::Uuid,
+            pattern => Regex::new(&format!("^(?:{pattern})$"))
+                .map(RouteConstraint::Pattern)
+                .map_err(|e| format!("invalid constraint pattern: {e}"))?,
+        };
+
+        Ok(constraint)
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        match self {
+            RouteConstraint::Int { min, max } => value
+                .parse::<i128>()
+                .is_ok_and(|value| (*min..=*max).contains(&value)),
+            RouteConstraint::Float => value.parse::<f64>().is_ok(),
+            RouteConstraint::Bool => value.parse::<bool>().is_ok(),
+            RouteConstraint::Uuid => is_uuid(value),
+            RouteConstraint::Pattern(regex) => regex.is_match(value),
+        }
+    }
+}
+
+fn int_constraint(min: impl Into<i128>, max: impl Into<i128>) -> RouteConstraint {
+    RouteConstraint::Int {
+        min: min.into(),
+        max: max.into(),
+    }
+}
+
+/// Hyphenated form only, e.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`.
+fn is_uuid(value: &str) -> bool {
+    let groups: Vec<_> = value.split('-').collect();
+    let lengths = [8, 4, 4, 4, 12];
+
+    groups.len() == lengths.len()
+        && groups.iter().zip(lengths).all(|(group, length)| {
+            group.len() == length && group.chars().all(|c| c.is_ascii_hexdigit())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RouteConstraint;
+
+    #[test]
+    fn int_constraints_check_range() {
+        let u8_constraint = RouteConstraint::parse("u8").unwrap();
+        let i8_constraint = RouteConstraint::parse("i8").unwrap();
+        let u64_constraint = RouteConstraint::parse("u64").unwrap();
+
+        assert!(u8_constraint.is_match("255"));
+        assert!(!u8_constraint.is_match("256"));
+        assert!(!u8_constraint.is_match("-1"));
+        assert!(i8_constraint.is_match("-128"));
+        assert!(!i8_constraint.is_match("128"));
+        assert!(u64_constraint.is_match("18446744073709551615"));
+        assert!(!u64_constraint.is_match("abc"));
+    }
+
+    #[test]
+    fn uuid_constraint() {
+        let constraint = RouteConstraint::parse("uuid").unwrap();
+
+        assert!(constraint.is_match("67e55044-10b1-426f-9247-bb680e5fe0c8"));
+        assert!(!constraint.is_match("67e55044-10b1-426f-9247-bb680e5fe0c"));
+        assert!(!constraint.is_match("67e55044x10b1-426f-9247-bb680e5fe0c8"));
+    }
+
+    #[test]
+    fn pattern_constraint_matches_whole_segment() {
+        let constraint = RouteConstraint::parse("[a-z-]+").unwrap();
+
+        assert!(constraint.is_match("hello-world"));
+        assert!(!constraint.is_match("Hello-world"));
+        assert!(!constraint.is_match("hello world"));
+        assert!(RouteConstraint::parse("[a-z").is_err());
+    }
+}
@@ -0,0 +1,654 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+use crate::percent_decode::percent_decode;
+use crate::route_constraint::RouteConstraint;
+use crate::uri_params::Route;
+
+/// Prefix tree over path segments. Static segments are preferred over `{param}`
+/// segments, which are preferred over `{*catch_all}` ones, so `/users/me` wins over
+/// `/users/{id}` regardless of registration order. A segment takes at most one
+/// constrained parameter (`{id:u64}`), tried before the unconstrained one, since two
+/// different constraints like `{id:u64}` and `{id:i64}` could both match a request.
+/// `{*catch_all}` needs at least one segment, `/static/{*path}` doesn't match `/static`.
+/// Request path segments are percent-decoded before matching.
+#[derive(Clone)]
+pub struct Router<T> {
+    root: Node<T>,
+    case_insensitive: bool,
+}
+
+#[derive(Clone)]
+struct Node<T> {
+    static_children: HashMap<String, Node<T>>,
+    param_children: Vec<ParamNode<T>>,
+    catch_all_child: Option<CatchAllNode<T>>,
+    endpoint: Option<Endpoint<T>>,
+}
+
+#[derive(Clone)]
+struct CatchAllNode<T> {
+    name: String,
+    endpoint: Endpoint<T>,
+}
+
+#[derive(Clone)]
+struct ParamNode<T> {
+    name: String,
+    constraint: Option<(String, RouteConstraint)>,
+    node: Node<T>,
+}
+
+#[derive(Clone)]
+struct Endpoint<T> {
+    route: String,
+    value: T,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RouteError {
+    /// Registered route which can't coexist with one registered before it.
+    Conflict {
+        route: String,
+        existing_route: String,
+    },
+    InvalidTemplate {
+        route: String,
+        reason: String,
+    },
+}
+
+enum Segment<'a> {
+    Static(&'a str),
+    Param(ParamSegment<'a>),
+    OptionalParam(ParamSegment<'a>),
+    CatchAll(&'a str),
+}
+
+struct ParamSegment<'a> {
+    name: &'a str,
+    constraint: Option<&'a str>,
+}
+
+pub struct RouteMatch<'a, T> {
+    pub value: &'a T,
+    pub params: Route,
+    /// Template the matched route was registered with, e.g. `/users/{id}`.
+    pub route: &'a str,
+}
+
+impl<T> Router<T> {
+    pub fn new() -> Router<T> {
+        Router {
+            root: Node::new(),
+            case_insensitive: false,
+        }
+    }
+
+    /// Static segments are matched ignoring case, parameter values keep theirs.
+    pub fn set_case_insensitive(&mut self, enabled: bool) {
+        self.case_insensitive = enabled;
+    }
+
+    /// Adds a route template. Besides static and `{param}` segments it may end with
+    /// a `{*catch_all}` segment capturing the rest of the path, or with `{param?}`
+    /// segments which can be omitted.
+    pub fn insert(&mut self, route: &str, value: T) -> Result<(), RouteError>
+    where
+        T: Clone,
+    {
+        let segments = parse_template(route)?;
+        let required_count = segments
+            .iter()
+            .take_while(|segment| !matches!(segment, Segment::OptionalParam(_)))
+            .count();
+
+        // `/archive/{year?}/{month?}` is registered as `/archive`, `/archive/{year}`
+        // and `/archive/{year}/{month}`.
+        for count in required_count..=segments.len() {
+            self.insert_segments(route, &segments[..count], value.clone())?;
+        }
+
+        Ok(())
+    }
+
+    fn insert_segments(
+        &mut self,
+        route: &str,
+        segments: &[Segment],
+        value: T,
+    ) -> Result<(), RouteError> {
+        let mut node = &mut self.root;
+        let endpoint = Endpoint {
+            route: route.to_string(),
+            value,
+        };
+
+        for segment in segments {
+            node = match segment {
+                Segment::Static(segment) => node
+                    .static_children
+                    .entry(segment.to_string())
+                    .or_insert_with(Node::new),
+                Segment::Param(param) | Segment::OptionalParam(param) => {
+                    let idx = node.get_param_child_idx(route, param)?;
+                    &mut node.param_children[idx].node
+                }
+                Segment::CatchAll(name) => {
+                    if let Some(catch_all) = &node.catch_all_child {
+                        return Err(RouteError::Conflict {
+                            route: route.to_string(),
+                            existing_route: catch_all.endpoint.route.clone(),
+                        });
+                    }
+
+                    node.catch_all_child = Some(CatchAllNode {
+                        name: name.to_string(),
+                        endpoint,
+                    });
+
+                    return Ok(());
+                }
+            };
+        }
+
+        if let Some(existing) = &node.endpoint {
+            return Err(RouteError::Conflict {
+                route: route.to_string(),
+                existing_route: existing.route.clone(),
+            });
+        }
+
+        node.endpoint = Some(endpoint);
+
+        Ok(())
+    }
+
+    pub fn find(&self, path: &str) -> Option<RouteMatch<'_, T>> {
+        let segments: Vec<Cow<str>> = split_path(path)?.map(percent_decode).collect();
+        let segments: Vec<&str> = segments.iter().map(|segment| segment.as_ref()).collect();
+        let mut params = vec![];
+
+        let endpoint = self
+            .root
+            .find(&segments, self.case_insensitive, &mut params)?;
+
+        Some(RouteMatch {
+            value: &endpoint.value,
+            params: Route(params.into_iter().collect()),
+            route: &endpoint.route,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.any_route().is_none()
+    }
+}
+
+impl<T> Default for Router<T> {
+    fn default() -> Router<T> {
+        Router::new()
+    }
+}
+
+impl<T> Node<T> {
+    fn new() -> Node<T> {
+        Node {
+            static_children: HashMap::new(),
+            param_children: vec![],
+            catch_all_child: None,
+            endpoint: None,
+        }
+    }
+
+    fn find<'a>(
+        &'a self,
+        segments: &[&str],
+        case_insensitive: bool,
+        params: &mut Vec<(String, String)>,
+    ) -> Option<&'a Endpoint<T>> {
+        let Some((segment, rest)) = segments.split_first() else {
+            return self.endpoint.as_ref();
+        };
+
+        if let Some(endpoint) = self
+            .find_static_child(segment, case_insensitive)
+            .and_then(|child| child.find(rest, case_insensitive, params))
+        {
+            return Some(endpoint);
+        }
+
+        for param in &self.param_children {
+            if let Some((_, constraint)) = &param.constraint {
+                if !constraint.is_match(segment) {
+                    continue;
+                }
+            }
+
+            params.push((param.name.clone(), segment.to_string()));
+
+            if let Some(endpoint) = param.node.find(rest, case_insensitive, params) {
+                return Some(endpoint);
+            }
+
+            params.pop();
+        }
+
+        let catch_all = self.catch_all_child.as_ref()?;
+        params.push((catch_all.name.clone(), segments.join("/")));
+
+        Some(&catch_all.endpoint)
+    }
+
+    /// Exact match first, with case-insensitive matching the alphabetically first of
+    /// the differently cased siblings.
+    fn find_static_child(&self, segment: &str, case_insensitive: bool) -> Option<&Node<T>> {
+        if let Some(child) = self.static_children.get(segment) {
+            return Some(child);
+        }
+
+        if !case_insensitive {
+            return None;
+        }
+
+        let segment = segment.to_lowercase();
+
+        self.static_children
+            .iter()
+            .filter(|(key, _)| key.to_lowercase() == segment)
+            .min_by_key(|(key, _)| key.as_str())
+            .map(|(_, child)| child)
+    }
+
+    fn any_route(&self) -> Option<String> {
+        if let Some(endpoint) = &self.endpoint {
+            return Some(endpoint.route.clone());
+        }
+
+        if let Some(catch_all) = &self.catch_all_child {
+            return Some(catch_all.endpoint.route.clone());
+        }
+
+        self.static_children
+            .values()
+            .chain(self.param_children.iter().map(|param| &param.node))
+            .find_map(|node| node.any_route())
+    }
+
+    /// Finds or adds the child for the parameter segment. Parameters with the same
+    /// constraint share the node, so they must have the same name, and a different
+    /// constraint on a segment which already has one is a conflict.
+    fn get_param_child_idx(
+        &mut self,
+        route: &str,
+        param: &ParamSegment,
+    ) -> Result<usize, RouteError> {
+        let existing_idx = self.param_children.iter().position(|child| {
+            child.constraint.as_ref().map(|(source, _)| source.as_str()) == param.constraint
+        });
+
+        if let Some(idx) = existing_idx {
+            let existing = &self.param_children[idx];
+
+            if existing.name != param.name {
+                return Err(RouteError::Conflict {
+                    route: route.to_string(),
+                    existing_route: existing.node.any_route().unwrap_or_default(),
+                });
+            }
+
+            return Ok(idx);
+        }
+
+        if param.constraint.is_some() {
+            let constrained = self
+                .param_children
+                .iter()
+                .find(|child| child.constraint.is_some());
+
+            if let Some(existing) = constrained {
+                return Err(RouteError::Conflict {
+                    route: route.to_string(),
+                    existing_route: existing.node.any_route().unwrap_or_default(),
+                });
+            }
+        }
+
+        let constraint = match param.constraint {
+            Some(source) => {
+                let constraint = RouteConstraint::parse(source).map_err(|reason| {
+                    RouteError::InvalidTemplate {
+                        route: route.to_string(),
+                        reason,
+                    }
+                })?;
+
+                Some((source.to_string(), constraint))
+            }
+            None => None,
+        };
+
+        // Unconstrained parameter matches anything, so it's always tried last.
+        let idx = match self.param_children.last() {
+            Some(last) if last.constraint.is_none() && constraint.is_some() => {
+                self.param_children.len() - 1
+            }
+            _ => self.param_children.len(),
+        };
+
+        self.param_children.insert(
+            idx,
+            ParamNode {
+                name: param.name.to_string(),
+                constraint,
+                node: Node::new(),
+            },
+        );
+
+        Ok(idx)
+    }
+}
+
+impl Display for RouteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteError::Conflict {
+                route,
+                existing_route,
+            } => write!(
+                f,
+                "Route \"{route}\" conflicts with already registered route \"{existing_route}\""
+            ),
+            RouteError::InvalidTemplate { route, reason } => {
+                write!(f, "Route \"{route}\" is invalid: {reason}")
+            }
+        }
+    }
+}
+
+/// `/a/b` gives `["a", "b"]`, paths not starting with `/` can't match any route.
+fn split_path(path: &str) -> Option<std::str::Split<'_, char>> {
+    path.strip_prefix('/').map(|path| path.split('/'))
+}
+
+fn parse_template(route: &str) -> Result<Vec<Segment<'_>>, RouteError> {
+    let invalid_template = |reason: &str| RouteError::InvalidTemplate {
+        route: route.to_string(),
+        reason: reason.to_string(),
+    };
+
+    // Segments are split on `/` below, so a constraint containing it would be cut in two.
+    let mut depth = 0usize;
+    for c in route.chars() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            '/' if depth > 0 => {
+                return Err(invalid_template(
+                    "a parameter matches a single segment, its constraint can't contain `/`",
+                ));
+            }
+            _ => (),
+        }
+    }
+
+    let segments: Vec<_> = route
+        .strip_prefix('/')
+        .unwrap_or(route)
+        .split('/')
+        .map(parse_segment)
+        .collect();
+
+    for (idx, segment) in segments.iter().enumerate() {
+        let is_last = idx == segments.len() - 1;
+
+        match segment {
+            Segment::CatchAll(_) if !is_last => {
+                return Err(invalid_template("catch-all segment must be the last one"));
+            }
+            Segment::CatchAll(name) if name.contains(':') => {
+                return Err(invalid_template("catch-all segment can't be constrained"));
+            }
+            Segment::Static(_) | Segment::Param(_) | Segment::CatchAll(_) if idx > 0 => {
+                if let Segment::OptionalParam(_) = segments[idx - 1] {
+                    return Err(invalid_template(
+                        "optional segments can only be followed by optional segments",
+                    ));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    Ok(segments)
+}
+
+fn parse_segment(segment: &str) -> Segment<'_> {
+    let name = segment
+        .strip_prefix('{')
+        .and_then(|segment| segment.strip_suffix('}'))
+        .filter(|name| !name.is_empty());
+
+    let Some(name) = name else {
+        return Segment::Static(segment);
+    };
+
+    if let Some(name) = name.strip_prefix('*') {
+        return Segment::CatchAll(name);
+    }
+
+    let (name, constraint) = match name.split_once(':') {
+        Some((name, constraint)) => (name, Some(constraint)),
+        None => (name, None),
+    };
+
+    match name.strip_suffix('?') {
+        Some(name) => Segment::OptionalParam(ParamSegment { name, constraint }),
+        None => Segment::Param(ParamSegment { name, constraint }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{RouteError, Router};
+    use crate::uri_params::Route;
+
+    #[test]
+    fn static_segment_preferred_over_param() {
+        let mut router = Router::new();
+        router.insert("/users/{id}", 1).unwrap();
+        router.insert("/users/me", 2).unwrap();
+
+        let found = router.find("/users/me").unwrap();
+        assert_eq!(*found.value, 2);
+        assert_eq!(found.params, Route(HashMap::new()));
+
+        let found = router.find("/users/42").unwrap();
+        assert_eq!(*found.value, 1);
+        assert_eq!(
+            found.params,
+            Route(HashMap::from([("id".to_string(), "42".to_string())]))
+        );
+    }
+
+    #[test]
+    fn falls_back_to_param_when_static_branch_fails() {
+        let mut router = Router::new();
+        router.insert("/users/me/settings", 1).unwrap();
+        router.insert("/users/{id}/posts", 2).unwrap();
+
+        let found = router.find("/users/me/posts").unwrap();
+        assert_eq!(*found.value, 2);
+        assert_eq!(
+            found.params,
+            Route(HashMap::from([("id".to_string(), "me".to_string())]))
+        );
+        assert!(router.find("/users/me").is_none());
+        assert!(router.find("users/me/posts").is_none());
+    }
+
+    #[test]
+    fn conflicting_routes_rejected() {
+        let mut router = Router::new();
+        router.insert("/users/{id}", 1).unwrap();
+
+        assert_eq!(
+            router.insert("/users/{id}", 2),
+            Err(RouteError::Conflict {
+                route: "/users/{id}".to_string(),
+                existing_route: "/users/{id}".to_string(),
+            })
+        );
+        assert_eq!(
+            router.insert("/users/{user_id}/posts", 3),
+            Err(RouteError::Conflict {
+                route: "/users/{user_id}/posts".to_string(),
+                existing_route: "/users/{id}".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn catch_all_captures_rest_of_path() {
+        let mut router = Router::new();
+        router.insert("/static/{*path}", 1).unwrap();
+        router.insert("/static/index.html", 2).unwrap();
+
+        let found = router.find("/static/css/site.css").unwrap();
+        assert_eq!(*found.value, 1);
+        assert_eq!(
+            found.params,
+            Route(HashMap::from([(
+                "path".to_string(),
+                "css/site.css".to_string()
+            )]))
+        );
+        assert_eq!(*router.find("/static/index.html").unwrap().value, 2);
+        assert!(router.find("/static").is_none());
+    }
+
+    #[test]
+    fn optional_trailing_segments() {
+        let mut router = Router::new();
+        router.insert("/archive/{year?}/{month?}", 1).unwrap();
+
+        assert_eq!(
+            router.find("/archive").unwrap().params,
+            Route(HashMap::new())
+        );
+        assert_eq!(
+            router.find("/archive/2023/11").unwrap().params,
+            Route(HashMap::from([
+                ("year".to_string(), "2023".to_string()),
+                ("month".to_string(), "11".to_string())
+            ]))
+        );
+        assert!(router.find("/archive/2023/11/01").is_none());
+    }
+
+    #[test]
+    fn constrained_params_checked_before_dispatch() {
+        let mut router = Router::new();
+        router.insert("/users/{name}", 1).unwrap();
+        router.insert("/users/{id:u64}", 2).unwrap();
+        router.insert("/posts/{slug:[a-z-]+}", 3).unwrap();
+        router.insert("/archive/{year?:u16}", 4).unwrap();
+
+        let found = router.find("/users/42").unwrap();
+        assert_eq!(*found.value, 2);
+        assert_eq!(
+            found.params,
+            Route(HashMap::from([("id".to_string(), "42".to_string())]))
+        );
+        assert_eq!(*router.find("/users/bob").unwrap().value, 1);
+        assert_eq!(*router.find("/posts/hello-world").unwrap().value, 3);
+        assert!(router.find("/posts/Hello").is_none());
+        assert!(router.find("/archive").is_some());
+        assert!(router.find("/archive/twenty").is_none());
+    }
+
+    #[test]
+    fn same_constraint_with_different_names_conflicts() {
+        let mut router = Router::new();
+        router.insert("/users/{id:u64}", 1).unwrap();
+
+        assert!(matches!(
+            router.insert("/users/{user_id:u64}/posts", 2),
+            Err(RouteError::Conflict { .. })
+        ));
+        assert!(router.insert("/users/{id:u64}/posts", 2).is_ok());
+    }
+
+    #[test]
+    fn invalid_templates_rejected() {
+        let mut router = Router::new();
+
+        assert!(matches!(
+            router.insert("/static/{*path}/edit", 1),
+            Err(RouteError::InvalidTemplate { .. })
+        ));
+        assert!(matches!(
+            router.insert("/archive/{year?}/posts", 1),
+            Err(RouteError::InvalidTemplate { .. })
+        ));
+        assert!(matches!(
+            router.insert("/posts/{slug:[a-z}", 1),
+            Err(RouteError::InvalidTemplate { .. })
+        ));
+        assert!(matches!(
+            router.insert("/files/{path:[a-z/]+}", 1),
+            Err(RouteError::InvalidTemplate { .. })
+        ));
+        assert!(router.insert("/archive/{year:\\d{4}}", 1).is_ok());
+    }
+
+    #[test]
+    fn different_constraints_on_one_segment_conflict() {
+        let mut router = Router::new();
+        router.insert("/users/{id:u64}", 1).unwrap();
+        router.insert("/users/{name}", 2).unwrap();
+
+        assert!(matches!(
+            router.insert("/users/{id:i64}", 3),
+            Err(RouteError::Conflict { .. })
+        ));
+        assert!(matches!(
+            router.insert("/users/{slug:[a-z]+}/posts", 3),
+            Err(RouteError::Conflict { .. })
+        ));
+        assert_eq!(*router.find("/users/-1").unwrap().value, 2);
+        assert_eq!(*router.find("/users/1").unwrap().value, 1);
+    }
+
+    #[test]
+    fn params_are_percent_decoded() {
+        let mut router = Router::new();
+        router.insert("/files/{name}", 1).unwrap();
+        router.insert("/static/{*path}", 2).unwrap();
+        router.insert("/caf\u{e9}", 3).unwrap();
+
+        let route_match = router.find("/files/my%20doc").unwrap();
+        assert_eq!(route_match.params.get("name"), Some("my doc"));
+
+        let route_match = router.find("/static/css/a%20b.css").unwrap();
+        assert_eq!(route_match.params.get("path"), Some("css/a b.css"));
+
+        assert_eq!(router.find("/caf%C3%A9").map(|m| *m.value), Some(3));
+    }
+
+    #[test]
+    fn case_insensitive_static_segments() {
+        let mut router = Router::new();
+        router.insert("/users/{id}", 1).unwrap();
+
+        assert!(router.find("/Users/Bob").is_none());
+
+        router.set_case_insensitive(true);
+
+        let route_match = router.find("/USERS/Bob").unwrap();
+        assert_eq!(route_match.params.get("id"), Some("Bob"));
+    }
+}
@@ -0,0 +1,126 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::listener::ListenAddr;
+
+/// Stops a running `WebApi`. Clones control the same server, so a handle can be moved
+/// to another thread or a signal handler before `run` blocks. A shutdown is final: it
+/// isn't reset when `run` returns, so running the same api again returns right away.
+#[derive(Clone, Default)]
+pub struct ServerHandle(Arc<ShutdownState>);
+
+#[derive(Default)]
+struct ShutdownState {
+    shutting_down: AtomicBool,
+    local_addrs: Mutex<Vec<ListenAddr>>,
+}
+
+impl ServerHandle {
+    pub fn new() -> ServerHandle {
+        ServerHandle::default()
+    }
+
+    /// Stops accepting connections and lets in-flight requests finish, `run` returns
+    /// once they are done or the shutdown timeout expires. Idle keep-alive connections
+    /// are closed right away.
+    pub fn shutdown(&self) {
+        if self.0.shutting_down.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        log::info!("shutting down");
+
+        // Wakes up the acceptors blocked in `accept`.
+        for addr in lock(&self.0.local_addrs).iter() {
+            match addr {
+                ListenAddr::Tcp(addr) => {
+                    let addr = wake_up_addr(*addr);
+                    let _ = TcpStream::connect_timeout(&addr, Duration::from_secs(1));
+                }
+                #[cfg(unix)]
+                ListenAddr::Unix(path) => {
+                    let _ = UnixStream::connect(path);
+                }
+                #[cfg(not(unix))]
+                ListenAddr::Unix(_) => {}
+            }
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.0.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Addresses the server listens on, empty until `run` has bound its listeners.
+    /// Useful with port 0, e.g. `127.0.0.1:0` in tests.
+    pub fn local_addrs(&self) -> Vec<ListenAddr> {
+        lock(&self.0.local_addrs).clone()
+    }
+
+    pub(crate) fn set_local_addrs(&self, addrs: Vec<ListenAddr>) {
+        *lock(&self.0.local_addrs) = addrs;
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Listener bound to all interfaces can't be connected to with the unspecified address
+/// on every platform, the loopback one is used instead.
+fn wake_up_addr(mut addr: SocketAddr) -> SocketAddr {
+    if addr.ip().is_unspecified() {
+        match addr {
+            SocketAddr::V4(_) => addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+            SocketAddr::V6(_) => addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        }
+    }
+
+    addr
+}
+
+/// Shuts the server down on the first `SIGINT` or `SIGTERM`.
+#[cfg(all(unix, feature = "signals"))]
+pub(crate) fn shutdown_on_signals(handle: ServerHandle) -> std::io::Result<()> {
+    use signal_hook::consts::{SIGINT, SIGTERM};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+
+    std::thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            log::info!("received signal {signal}");
+            handle.shutdown();
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use crate::listener::ListenAddr;
+
+    use super::ServerHandle;
+
+    #[test]
+    fn shutdown_wakes_up_acceptor() {
+        let listener = TcpListener::bind("0.0.0.0:0").unwrap();
+        let handle = ServerHandle::new();
+        handle.set_local_addrs(vec![ListenAddr::Tcp(listener.local_addr().unwrap())]);
+
+        assert!(!handle.is_shutting_down());
+
+        handle.shutdown();
+        handle.shutdown();
+
+        assert!(handle.is_shutting_down());
+        assert!(listener.accept().is_ok());
+    }
+}
@@ -0,0 +1,298 @@
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Workers above the minimum exit after being idle for this long.
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PoolConfig {
+    pub min_workers: usize,
+    pub max_workers: usize,
+    pub queue_capacity: usize,
+}
+
+/// Pool of worker threads calling `handler` for every queued item. Starts with the
+/// minimum number of workers and adds more (up to the maximum) while all of them are
+/// busy, the extra workers exit after staying idle for a while.
+pub(crate) struct ThreadPool<T> {
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T> {
+    config: PoolConfig,
+    handler: Box<dyn Fn(T) + Send + Sync>,
+    state: Mutex<PoolState<T>>,
+    job_available: Condvar,
+    space_available: Condvar,
+    workers_changed: Condvar,
+}
+
+struct PoolState<T> {
+    queue: VecDeque<T>,
+    workers: usize,
+    idle_workers: usize,
+    closed: bool,
+    next_worker_id: usize,
+}
+
+/// Keeps the worker count right however the worker thread ends, and replaces workers
+/// killed by a panic outside of the handler.
+struct WorkerGuard<T: Send + 'static> {
+    shared: Arc<Shared<T>>,
+    id: usize,
+}
+
+impl<T: Send + 'static> ThreadPool<T> {
+    pub fn new<Handler>(config: PoolConfig, handler: Handler) -> ThreadPool<T>
+    where
+        Handler: Fn(T) + Send + Sync + 'static,
+    {
+        let shared = Arc::new(Shared {
+            config,
+            handler: Box::new(handler),
+            state: Mutex::new(PoolState {
+                queue: VecDeque::new(),
+                workers: 0,
+                idle_workers: 0,
+                closed: false,
+                next_worker_id: 0,
+            }),
+            job_available: Condvar::new(),
+            space_available: Condvar::new(),
+            workers_changed: Condvar::new(),
+        });
+
+        {
+            let mut state = shared.lock();
+
+            for _ in 0..config.min_workers {
+                spawn_worker(&shared, &mut state);
+            }
+        }
+
+        ThreadPool { shared }
+    }
+
+    /// Queues the item, starting a new worker if all of them are busy. When the queue is
+    /// full the call waits for room if `wait` is set, otherwise the item is given back.
+    pub fn execute(&self, item: T, wait: bool) -> Result<(), T> {
+        let shared = &self.shared;
+        let mut state = shared.lock();
+
+        loop {
+            if state.closed {
+                return Err(item);
+            }
+
+            if state.idle_workers == 0 && state.workers < shared.config.max_workers {
+                spawn_worker(shared, &mut state);
+            }
+
+            if state.queue.len() < shared.config.queue_capacity + state.idle_workers {
+                state.queue.push_back(item);
+                shared.job_available.notify_one();
+                return Ok(());
+            }
+
+            if !wait {
+                return Err(item);
+            }
+
+            state = shared
+                .space_available
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Stops taking new items and waits until the queued ones are handled, `false` if
+    /// some workers were still busy when the timeout expired.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        let shared = &self.shared;
+        let deadline = Instant::now() + timeout;
+        let mut state = shared.lock();
+
+        state.closed = true;
+        shared.job_available.notify_all();
+
+        while state.workers > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+
+            if remaining.is_zero() {
+                return false;
+            }
+
+            state = shared
+                .workers_changed
+                .wait_timeout(state, remaining)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+
+        true
+    }
+}
+
+impl<T> Shared<T> {
+    /// Poisoned lock still holds a consistent state, the handler runs outside of it.
+    fn lock(&self) -> MutexGuard<'_, PoolState<T>> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn spawn_worker<T: Send + 'static>(shared: &Arc<Shared<T>>, state: &mut PoolState<T>) {
+    state.workers += 1;
+    state.next_worker_id += 1;
+
+    let guard = WorkerGuard {
+        shared: Arc::clone(shared),
+        id: state.next_worker_id,
+    };
+
+    thread::spawn(move || {
+        while let Some(item) = next_item(&guard.shared) {
+            let handled = panic::catch_unwind(AssertUnwindSafe(|| (guard.shared.handler)(item)));
+
+            if handled.is_err() {
+                log::error!("worker {} panicked while handling a job", guard.id);
+            }
+        }
+    });
+}
+
+/// Next queued item, `None` once the pool is closed and drained or the worker has
+/// been idle for too long and isn't needed to keep the minimum.
+fn next_item<T>(shared: &Shared<T>) -> Option<T> {
+    let mut state = shared.lock();
+
+    loop {
+        if let Some(item) = state.queue.pop_front() {
+            shared.space_available.notify_one();
+            return Some(item);
+        }
+
+        if state.closed {
+            return None;
+        }
+
+        state.idle_workers += 1;
+        // Idle worker makes room for an item waiting for a full queue.
+        shared.space_available.notify_one();
+
+        let (new_state, wait_result) = shared
+            .job_available
+            .wait_timeout(state, WORKER_IDLE_TIMEOUT)
+            .unwrap_or_else(PoisonError::into_inner);
+
+        state = new_state;
+        state.idle_workers -= 1;
+
+        if wait_result.timed_out()
+            && state.queue.is_empty()
+            && state.workers > shared.config.min_workers
+        {
+            return None;
+        }
+    }
+}
+
+impl<T: Send + 'static> Drop for WorkerGuard<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.workers -= 1;
+
+        if thread::panicking() && !state.closed {
+            log::error!("worker {} died, starting a new one", self.id);
+            spawn_worker(&self.shared, &mut state);
+        }
+
+        self.shared.workers_changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::mpsc;
+    use std::sync::{Arc, Barrier};
+    use std::time::Duration;
+
+    use super::{PoolConfig, ThreadPool};
+
+    #[test]
+    fn handles_every_item_before_shutdown() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let pool_handled = Arc::clone(&handled);
+        let config = PoolConfig {
+            min_workers: 1,
+            max_workers: 4,
+            queue_capacity: 100,
+        };
+        let pool = ThreadPool::new(config, move |value: usize| {
+            pool_handled.fetch_add(value, Ordering::SeqCst);
+        });
+
+        for value in 1..=10 {
+            pool.execute(value, true).unwrap();
+        }
+
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        assert_eq!(handled.load(Ordering::SeqCst), 55);
+        assert_eq!(pool.execute(1, true), Err(1));
+    }
+
+    #[test]
+    fn gives_items_back_when_queue_is_full() {
+        let barrier = Arc::new(Barrier::new(2));
+        let pool_barrier = Arc::clone(&barrier);
+        let (started_sender, started_receiver) = mpsc::channel();
+        let config = PoolConfig {
+            min_workers: 1,
+            max_workers: 1,
+            queue_capacity: 1,
+        };
+        let pool = ThreadPool::new(config, move |value: usize| {
+            started_sender.send(value).unwrap();
+            pool_barrier.wait();
+        });
+
+        pool.execute(1, false).unwrap();
+        assert_eq!(started_receiver.recv().unwrap(), 1);
+
+        // The only worker is busy, one item fits into the queue.
+        pool.execute(2, false).unwrap();
+        assert_eq!(pool.execute(3, false), Err(3));
+
+        barrier.wait();
+        assert_eq!(started_receiver.recv().unwrap(), 2);
+        barrier.wait();
+
+        assert!(pool.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn workers_survive_handler_panics() {
+        let (sender, receiver) = mpsc::channel();
+        let config = PoolConfig {
+            min_workers: 1,
+            max_workers: 1,
+            queue_capacity: 10,
+        };
+        let pool = ThreadPool::new(config, move |value: usize| {
+            if value == 0 {
+                panic!("bad value");
+            }
+
+            sender.send(value).unwrap();
+        });
+
+        pool.execute(0, true).unwrap();
+        pool.execute(7, true).unwrap();
+
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)), Ok(7));
+        assert!(pool.shutdown(Duration::from_secs(5)));
+    }
+}
@@ -0,0 +1,357 @@
+use std::fs;
+use std::io::{self, Error, ErrorKind, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+
+use crate::connection::Transport;
+use crate::extract::ClientCertificate;
+use crate::shutdown::ServerHandle;
+
+const RELOAD_INTERVAL: u64 = 60;
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether clients of a TLS listener have to present a certificate, see
+/// `WebApi::tls_client_auth`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClientAuth {
+    /// Clients without a certificate are served too, handlers asking for the
+    /// `ClientCertificate` answer them with 401.
+    Optional,
+    /// Handshake fails unless the client presents a certificate signed by the CA.
+    Required,
+}
+
+/// TLS setup collected by the `WebApi` builder. The files are read when the server
+/// starts and read again whenever they change.
+#[derive(Clone)]
+pub(crate) struct TlsSettings {
+    /// The first one is the default certificate, the others are chosen by SNI.
+    certificates: Vec<CertificateFiles>,
+    client_auth: Option<(PathBuf, ClientAuth)>,
+    pub reload_interval: Duration,
+}
+
+#[derive(Clone, Debug)]
+struct CertificateFiles {
+    server_name: Option<String>,
+    cert_pem: PathBuf,
+    key_pem: PathBuf,
+}
+
+/// Certificates in use and the modification time of their files when they were loaded.
+#[derive(Debug)]
+struct CertificateResolver {
+    provider: Arc<CryptoProvider>,
+    certificates: RwLock<Vec<LoadedCertificate>>,
+}
+
+#[derive(Debug)]
+struct LoadedCertificate {
+    files: CertificateFiles,
+    key: Arc<CertifiedKey>,
+    modified: Option<SystemTime>,
+}
+
+/// Starts TLS sessions on accepted connections.
+#[derive(Clone)]
+pub(crate) struct TlsAcceptor {
+    config: Arc<ServerConfig>,
+    resolver: Arc<CertificateResolver>,
+    reload_interval: Duration,
+}
+
+impl TlsSettings {
+    pub fn new(cert_pem: &Path, key_pem: &Path) -> TlsSettings {
+        TlsSettings {
+            certificates: vec![CertificateFiles {
+                server_name: None,
+                cert_pem: cert_pem.to_path_buf(),
+                key_pem: key_pem.to_path_buf(),
+            }],
+            client_auth: None,
+            reload_interval: Duration::from_secs(RELOAD_INTERVAL),
+        }
+    }
+
+    /// `server_name` may start with `*.` to match any single-label subdomain.
+    pub fn add_certificate(&mut self, server_name: &str, cert_pem: &Path, key_pem: &Path) {
+        self.certificates.push(CertificateFiles {
+            server_name: Some(server_name.to_ascii_lowercase()),
+            cert_pem: cert_pem.to_path_buf(),
+            key_pem: key_pem.to_path_buf(),
+        });
+    }
+
+    pub fn set_client_auth(&mut self, ca_pem: &Path, client_auth: ClientAuth) {
+        self.client_auth = Some((ca_pem.to_path_buf(), client_auth));
+    }
+
+    /// Loads the certificates, fails if one can't be read or doesn't match its key.
+    pub fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let provider = Arc::new(ring::default_provider());
+        let mut certificates = vec![];
+
+        for files in &self.certificates {
+            certificates.push(LoadedCertificate {
+                modified: modified(files),
+                key: Arc::new(load_certified_key(&provider, files)?),
+                files: files.clone(),
+            });
+        }
+
+        let resolver = Arc::new(CertificateResolver {
+            provider: Arc::clone(&provider),
+            certificates: RwLock::new(certificates),
+        });
+
+        let builder = ServerConfig::builder_with_provider(Arc::clone(&provider))
+            .with_safe_default_protocol_versions()
+            .map_err(invalid_data)?;
+
+        let builder = match &self.client_auth {
+            None => builder.with_no_client_auth(),
+            Some((ca_pem, client_auth)) => {
+                let mut roots = RootCertStore::empty();
+
+                for certificate in load_certificates(ca_pem)? {
+                    roots.add(certificate).map_err(invalid_data)?;
+                }
+
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+                let verifier = match client_auth {
+                    ClientAuth::Optional => verifier.allow_unauthenticated(),
+                    ClientAuth::Required => verifier,
+                };
+
+                builder.with_client_cert_verifier(verifier.build().map_err(invalid_data)?)
+            }
+        };
+
+        let mut config = builder.with_cert_resolver(Arc::clone(&resolver) as _);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(TlsAcceptor {
+            config: Arc::new(config),
+            resolver,
+            reload_interval: self.reload_interval,
+        })
+    }
+}
+
+impl TlsAcceptor {
+    /// TLS session over the connection, the handshake happens on the first read.
+    pub fn accept(&self, stream: TcpStream) -> io::Result<TlsStream> {
+        let connection = ServerConnection::new(Arc::clone(&self.config)).map_err(invalid_data)?;
+
+        Ok(StreamOwned::new(connection, stream))
+    }
+
+    #[cfg(feature = "tokio-tls")]
+    pub fn async_acceptor(&self) -> tokio_rustls::TlsAcceptor {
+        tokio_rustls::TlsAcceptor::from(Arc::clone(&self.config))
+    }
+
+    /// Checks the certificate files every `reload_interval` until the server shuts down,
+    /// changed ones are loaded for the following handshakes.
+    pub fn watch_certificates(&self, handle: ServerHandle) {
+        let resolver = Arc::clone(&self.resolver);
+        let reload_interval = self.reload_interval;
+
+        thread::spawn(move || {
+            let mut checked_at = Instant::now();
+
+            while !handle.is_shutting_down() {
+                thread::sleep(WATCH_POLL_INTERVAL.min(reload_interval));
+
+                if checked_at.elapsed() >= reload_interval {
+                    resolver.reload_changed();
+                    checked_at = Instant::now();
+                }
+            }
+        });
+    }
+}
+
+impl CertificateResolver {
+    /// A certificate which fails to load keeps the previous one in use, it's tried
+    /// again on the next check.
+    fn reload_changed(&self) {
+        let changed: Vec<_> = self
+            .read()
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, loaded)| {
+                let modified = modified(&loaded.files);
+
+                (modified != loaded.modified).then(|| (idx, loaded.files.clone(), modified))
+            })
+            .collect();
+
+        for (idx, files, modified) in changed {
+            match load_certified_key(&self.provider, &files) {
+                Ok(key) => {
+                    log::info!("reloaded TLS certificate {}", files.cert_pem.display());
+
+                    let mut certificates = self
+                        .certificates
+                        .write()
+                        .unwrap_or_else(PoisonError::into_inner);
+                    certificates[idx].key = Arc::new(key);
+                    certificates[idx].modified = modified;
+                }
+                Err(e) => log::warn!("failed to reload TLS certificate: {e}"),
+            }
+        }
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Vec<LoadedCertificate>> {
+        self.certificates
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    /// Certificate registered for the server name the client asked for, the default
+    /// one if it didn't send SNI or no certificate matches the name.
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certificates = self.read();
+        let server_name = client_hello.server_name().map(str::to_ascii_lowercase);
+
+        let matching = server_name.and_then(|server_name| {
+            certificates.iter().find(|loaded| {
+                loaded
+                    .files
+                    .server_name
+                    .as_deref()
+                    .is_some_and(|pattern| matches_server_name(pattern, &server_name))
+            })
+        });
+
+        matching
+            .or(certificates.first())
+            .map(|loaded| Arc::clone(&loaded.key))
+    }
+}
+
+pub(crate) type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+impl Transport for TlsStream {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.sock.peer_addr().ok()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.sock.set_write_timeout(timeout)
+    }
+
+    fn client_certificate(&self) -> Option<ClientCertificate> {
+        client_certificate(&self.conn)
+    }
+
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.flush();
+    }
+}
+
+/// End-entity certificate the client presented during the handshake.
+pub(crate) fn client_certificate(connection: &ServerConnection) -> Option<ClientCertificate> {
+    connection
+        .peer_certificates()?
+        .first()
+        .map(|certificate| ClientCertificate(certificate.to_vec()))
+}
+
+fn matches_server_name(pattern: &str, server_name: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(parent) => server_name
+            .split_once('.')
+            .is_some_and(|(_, server_parent)| server_parent == parent),
+        None => pattern == server_name,
+    }
+}
+
+fn load_certified_key(
+    provider: &CryptoProvider,
+    files: &CertificateFiles,
+) -> io::Result<CertifiedKey> {
+    let certificates = load_certificates(&files.cert_pem)?;
+    let key =
+        PrivateKeyDer::from_pem_file(&files.key_pem).map_err(|e| file_error(&files.key_pem, e))?;
+
+    CertifiedKey::from_der(certificates, key, provider).map_err(|e| file_error(&files.key_pem, e))
+}
+
+fn load_certificates(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| file_error(path, e))?;
+
+    if certificates.is_empty() {
+        return Err(file_error(path, "no certificates found"));
+    }
+
+    Ok(certificates)
+}
+
+/// Latest modification time of the certificate and key files.
+fn modified(files: &CertificateFiles) -> Option<SystemTime> {
+    let modified = |path: &Path| fs::metadata(path).and_then(|metadata| metadata.modified());
+
+    Some(
+        modified(&files.cert_pem)
+            .ok()?
+            .max(modified(&files.key_pem).ok()?),
+    )
+}
+
+fn file_error(path: &Path, error: impl std::fmt::Display) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("{}: {error}", path.display()),
+    )
+}
+
+fn invalid_data(error: impl std::fmt::Display) -> Error {
+    Error::new(ErrorKind::InvalidData, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{matches_server_name, TlsSettings};
+
+    #[test]
+    fn server_names_and_wildcards() {
+        assert!(matches_server_name("api.example.com", "api.example.com"));
+        assert!(!matches_server_name("api.example.com", "www.example.com"));
+        assert!(matches_server_name("*.example.com", "www.example.com"));
+        assert!(!matches_server_name("*.example.com", "example.com"));
+        assert!(!matches_server_name("*.example.com", "a.b.example.com"));
+    }
+
+    #[test]
+    fn missing_certificate_fails_to_start() {
+        let settings = TlsSettings::new(Path::new("missing.pem"), Path::new("missing.key"));
+        let error = settings.acceptor().err().unwrap();
+
+        assert!(error.to_string().starts_with("missing.pem"));
+    }
+}
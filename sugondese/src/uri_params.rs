@@ -1,7 +1,45 @@
 use std::collections::HashMap;
+use std::str::FromStr;
 
 #[derive(Debug, PartialEq)]
 pub struct Route(pub HashMap<String, String>);
 
+/// Decoded query params in the order they appear in the uri. A key may repeat
+/// (`?tag=a&tag=b`) and flags without a value (`?debug`) have an empty value.
 #[derive(Debug, PartialEq)]
-pub struct Query(pub HashMap<String, String>);
+pub struct Query(pub Vec<(String, String)>);
+
+impl Route {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(|value| value.as_str())
+    }
+
+    /// Typed parameter value. Values of constrained segments like `{id:u64}` are
+    /// checked by the router, so parsing them into the same type always succeeds.
+    pub fn parse<T: FromStr>(&self, name: &str) -> Option<T> {
+        self.get(name)?.parse().ok()
+    }
+}
+
+impl Query {
+    /// First value of the param.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All values of a repeated param, in order.
+    pub fn get_all(&self, name: &str) -> Vec<&str> {
+        self.0
+            .iter()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|(key, _)| key == name)
+    }
+}
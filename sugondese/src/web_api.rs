@@ -1,103 +1,706 @@
 use std::{
-    collections::HashMap,
-    io::Result,
-    net::TcpListener,
-    sync::{Arc, Mutex},
-    thread::{self, JoinHandle},
+    cell::Cell,
+    io::{Result, Write},
+    net::{TcpListener, ToSocketAddrs},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 
 use crate::{
+    access_log::AccessLogFormat,
+    app_state::AppState,
+    connection::{log_rejected, serve_connection},
+    handler::Handler,
     http_handler_info::HttpHandlerInfo,
+    http_request::HttpRequest,
     http_response::{HttpResponse, HttpStatus},
+    listener::{bind_tcp, is_connection_error, BindAddr, Connection, Listener},
     method_verb::HttpMethod,
-    request_parser::{handle_request, parse_request, return_response, HttpRequestHandler},
+    middleware::{Middleware, Next},
+    path_normalize::normalize_path,
+    request_parser::{
+        match_handler, parse_route, write_response, HttpRequestHandler, MatchedHandler,
+        RequestLimits,
+    },
+    router::Router,
+    shutdown::ServerHandle,
+    thread_pool::{PoolConfig, ThreadPool},
 };
 
-pub struct WebApi<'a> {
-    addr: &'a str,
-    threads_num: usize,
+#[cfg(feature = "tokio-tls")]
+use crate::async_connection::serve_tls_connection_async;
+#[cfg(feature = "tls")]
+use crate::tls::{ClientAuth, TlsSettings};
+#[cfg(feature = "tokio")]
+use crate::{
+    async_connection::serve_connection_async,
+    listener::{accept_any, AsyncConnection, AsyncListener},
+};
+
+const ALLOW_HEADER: &str = "Allow";
+const LOCATION_HEADER: &str = "Location";
+const KEEP_ALIVE_TIMEOUT: u64 = 5;
+const IDLE_TIMEOUT: u64 = 5;
+const HEADER_TIMEOUT: u64 = 5;
+const BODY_TIMEOUT: u64 = 30;
+const WRITE_TIMEOUT: u64 = 30;
+const SHUTDOWN_TIMEOUT: u64 = 30;
+const QUEUE_CAPACITY: usize = 128;
+const REJECT_WRITE_TIMEOUT: u64 = 1;
+/// Pause after an accept error which would repeat right away, e.g. EMFILE.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+const CONNECTION_HEADER: &str = "Connection";
+const ROUTABLE_METHODS: [HttpMethod; 5] = [
+    HttpMethod::Get,
+    HttpMethod::Post,
+    HttpMethod::Put,
+    HttpMethod::Delete,
+    HttpMethod::Patch,
+];
+const MAX_REQUESTS_PER_CONNECTION: usize = 100;
+
+pub struct WebApi {
+    addrs: Vec<BindAddr>,
+    pool: PoolConfig,
+    backlog_policy: BacklogPolicy,
     endpoints: Endpoints,
+    config: ServerConfig,
+    handle: ServerHandle,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsSettings>,
+    #[cfg(unix)]
+    socket_activation: bool,
 }
 
 #[derive(Clone)]
-struct Endpoints {
-    get_endpoints: HashMap<String, HttpRequestHandler>,
-    post_endpoints: HashMap<String, HttpRequestHandler>,
-    delete_endpoints: HashMap<String, HttpRequestHandler>,
-    put_endpoints: HashMap<String, HttpRequestHandler>,
+pub(crate) struct ServerConfig {
+    pub keep_alive: bool,
+    pub keep_alive_timeout: Duration,
+    pub idle_timeout: Duration,
+    pub header_timeout: Duration,
+    pub body_timeout: Duration,
+    pub write_timeout: Duration,
+    pub limits: RequestLimits,
+    pub max_requests_per_connection: usize,
+    pub access_log_format: AccessLogFormat,
+    pub shutdown_timeout: Duration,
+    pub panic_hook: Option<Arc<PanicHook>>,
+    #[cfg(all(unix, feature = "signals"))]
+    pub shutdown_on_signals: bool,
+}
+
+#[derive(Clone)]
+pub(crate) struct Endpoints {
+    get_endpoints: Router<HttpRequestHandler>,
+    post_endpoints: Router<HttpRequestHandler>,
+    delete_endpoints: Router<HttpRequestHandler>,
+    put_endpoints: Router<HttpRequestHandler>,
+    patch_endpoints: Router<HttpRequestHandler>,
+    trailing_slash: TrailingSlash,
+    state: AppState,
+    pub middlewares: Vec<Arc<dyn Middleware + Send + Sync>>,
+}
+
+/// Called with every panic caught in a handler, a middleware or a chunked body.
+pub type PanicHook = dyn Fn(&HandlerPanic) + Send + Sync;
+
+/// Panic caught while handling a request, the client got `500 Internal Server Error`.
+/// If a chunked body panicked after the response started, the connection was closed.
+#[derive(Debug)]
+pub struct HandlerPanic {
+    pub method: HttpMethod,
+    pub uri: String,
+    /// Panic message, empty if the payload wasn't a string.
+    pub message: String,
+}
+
+/// What happens to a new connection when every worker is busy and the queue of
+/// accepted connections is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BacklogPolicy {
+    /// Stop accepting until there's room in the queue, further clients wait in the
+    /// listen backlog of the OS.
+    Queue,
+    /// Answer with `503 Service Unavailable` and close the connection.
+    Reject,
+    /// Close the connection without a response.
+    Drop,
 }
 
-impl<'a> WebApi<'a> {
-    pub fn new(addr: &'a str, threads_num: usize) -> WebApi {
-        if threads_num <= 0 {
+/// How requests which differ from a registered route only by the trailing slash are
+/// handled.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrailingSlash {
+    /// `/users` and `/users/` are different routes.
+    Strict,
+    /// Request is served by the route with or without the trailing slash.
+    Ignore,
+    /// Client is redirected to the registered route with `308 Permanent Redirect`.
+    Redirect,
+}
+
+impl WebApi {
+    /// Api listening on `addr` (e.g. `"127.0.0.1:8080"` or a `SocketAddr`) and serving
+    /// connections with `threads_num` worker threads, see `min_workers` and
+    /// `max_workers` for a pool which grows on demand. An address which can't be
+    /// resolved makes `run` fail.
+    pub fn new<A: ToSocketAddrs>(addr: A, threads_num: usize) -> WebApi {
+        if threads_num == 0 {
             panic!("Threads number must be more than 0");
         }
 
         WebApi {
-            addr,
-            threads_num,
+            addrs: vec![BindAddr::tcp(addr)],
+            pool: PoolConfig {
+                min_workers: threads_num,
+                max_workers: threads_num,
+                queue_capacity: QUEUE_CAPACITY,
+            },
+            backlog_policy: BacklogPolicy::Queue,
             endpoints: Endpoints {
-                get_endpoints: HashMap::new(),
-                post_endpoints: HashMap::new(),
-                delete_endpoints: HashMap::new(),
-                put_endpoints: HashMap::new(),
+                get_endpoints: Router::new(),
+                post_endpoints: Router::new(),
+                delete_endpoints: Router::new(),
+                put_endpoints: Router::new(),
+                patch_endpoints: Router::new(),
+                trailing_slash: TrailingSlash::Strict,
+                state: AppState::new(),
+                middlewares: vec![],
+            },
+            config: ServerConfig {
+                keep_alive: true,
+                keep_alive_timeout: Duration::from_secs(KEEP_ALIVE_TIMEOUT),
+                idle_timeout: Duration::from_secs(IDLE_TIMEOUT),
+                header_timeout: Duration::from_secs(HEADER_TIMEOUT),
+                body_timeout: Duration::from_secs(BODY_TIMEOUT),
+                write_timeout: Duration::from_secs(WRITE_TIMEOUT),
+                limits: RequestLimits::default(),
+                max_requests_per_connection: MAX_REQUESTS_PER_CONNECTION,
+                access_log_format: AccessLogFormat::Common,
+                shutdown_timeout: Duration::from_secs(SHUTDOWN_TIMEOUT),
+                panic_hook: None,
+                #[cfg(all(unix, feature = "signals"))]
+                shutdown_on_signals: false,
             },
+            handle: ServerHandle::new(),
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(unix)]
+            socket_activation: false,
+        }
+    }
+
+    /// Number of worker threads kept running even when there is nothing to do.
+    pub fn min_workers(mut self, count: usize) -> Self {
+        self.pool.min_workers = count;
+        self.pool.max_workers = self.pool.max_workers.max(count);
+        self
+    }
+
+    /// Upper limit of worker threads, more are started while all of them are busy.
+    pub fn max_workers(mut self, count: usize) -> Self {
+        if count == 0 {
+            panic!("Max workers number must be more than 0");
+        }
+
+        self.pool.max_workers = count;
+        self.pool.min_workers = self.pool.min_workers.min(count);
+        self
+    }
+
+    /// How many accepted connections may wait for a free worker, 128 by default.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.pool.queue_capacity = capacity;
+        self
+    }
+
+    /// What to do with connections which don't fit into the full queue, `Queue` by
+    /// default.
+    pub fn backlog_policy(mut self, policy: BacklogPolicy) -> Self {
+        self.backlog_policy = policy;
+        self
+    }
+
+    /// Sets how paths with or without a trailing slash are matched, `Strict` by default.
+    pub fn trailing_slash(mut self, policy: TrailingSlash) -> Self {
+        self.endpoints.trailing_slash = policy;
+        self
+    }
+
+    /// Shares the value with handlers taking a `State<T>` param. Values of different
+    /// types can be added, adding a value of the same type replaces the previous one.
+    pub fn with_state<T>(mut self, state: T) -> Self
+    where
+        T: Send + Sync + 'static,
+    {
+        self.endpoints.state.insert(state);
+        self
+    }
+
+    /// Adds a middleware layer around the handlers. Layers run in the order they were
+    /// added, the first one sees the request first and the response last.
+    pub fn wrap<M>(mut self, middleware: M) -> Self
+    where
+        M: Middleware + Send + Sync + 'static,
+    {
+        self.endpoints.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Matches static route segments ignoring case, `/Users/42` is served by
+    /// `/users/{id}`. Disabled by default.
+    pub fn case_insensitive_routes(mut self, enabled: bool) -> Self {
+        for router in [
+            &mut self.endpoints.get_endpoints,
+            &mut self.endpoints.post_endpoints,
+            &mut self.endpoints.delete_endpoints,
+            &mut self.endpoints.put_endpoints,
+            &mut self.endpoints.patch_endpoints,
+        ] {
+            router.set_case_insensitive(enabled);
+        }
+
+        self
+    }
+
+    /// Format of the access log line logged for every request with the `log` crate
+    /// (target `sugondese::access`, level `info`). Common Log Format by default.
+    pub fn access_log_format(mut self, format: AccessLogFormat) -> Self {
+        self.config.access_log_format = format;
+        self
+    }
+
+    /// Reports panics in handlers, middlewares and chunked bodies, e.g. to an error
+    /// tracker. Panics are answered with `500 Internal Server Error` either way, unless
+    /// a chunked body was already being sent, then the connection is closed.
+    pub fn on_panic<Hook>(mut self, hook: Hook) -> Self
+    where
+        Hook: Fn(&HandlerPanic) + Send + Sync + 'static,
+    {
+        self.config.panic_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// How long `run` waits for in-flight requests after a shutdown was requested,
+    /// 30 seconds by default.
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Shuts the server down gracefully on `SIGINT` or `SIGTERM`.
+    #[cfg(all(unix, feature = "signals"))]
+    pub fn shutdown_on_signals(mut self) -> Self {
+        self.config.shutdown_on_signals = true;
+        self
+    }
+
+    /// Listens on one more address, e.g. `"[::]:8080"` next to `"0.0.0.0:8080"` or
+    /// another port. Connections from all listeners are served by the same workers.
+    pub fn bind<A: ToSocketAddrs>(mut self, addr: A) -> Self {
+        self.addrs.push(BindAddr::tcp(addr));
+        self
+    }
+
+    /// Listens on a Unix domain socket at `path` too. The socket file is removed when
+    /// the server stops.
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<std::path::Path>>(mut self, path: P) -> Self {
+        self.addrs.push(BindAddr::Unix(path.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Serves the sockets passed by a service manager like systemd (`LISTEN_FDS`)
+    /// instead of binding the added addresses, which are still used when the process
+    /// wasn't socket activated. The sockets stay open between restarts, so clients
+    /// connecting while a new process starts wait in the listen backlog. They are
+    /// served as plain HTTP.
+    #[cfg(unix)]
+    pub fn socket_activation(mut self) -> Self {
+        self.socket_activation = true;
+        self
+    }
+
+    /// Serves HTTPS on `addr` next to the other listeners. `cert_pem` has the
+    /// certificate chain and `key_pem` the private key, both are read when the server
+    /// starts and again whenever the files change. Panics if called twice, other
+    /// server names get their certificates with `tls_certificate`. `run_async` needs
+    /// the `tokio-tls` feature to serve it.
+    #[cfg(feature = "tls")]
+    pub fn bind_tls<A: ToSocketAddrs, P: AsRef<std::path::Path>>(
+        mut self,
+        addr: A,
+        cert_pem: P,
+        key_pem: P,
+    ) -> Self {
+        if self.tls.is_some() {
+            panic!("bind_tls can be called only once, add certificates with tls_certificate");
+        }
+
+        self.addrs
+            .push(BindAddr::Tls(crate::listener::resolve(addr)));
+        self.tls = Some(TlsSettings::new(cert_pem.as_ref(), key_pem.as_ref()));
+        self
+    }
+
+    /// Certificate for clients asking for `server_name` with SNI, e.g. `api.example.com`
+    /// or `*.example.com`. Others get the certificate passed to `bind_tls`.
+    #[cfg(feature = "tls")]
+    pub fn tls_certificate<P: AsRef<std::path::Path>>(
+        mut self,
+        server_name: &str,
+        cert_pem: P,
+        key_pem: P,
+    ) -> Self {
+        self.tls_settings()
+            .add_certificate(server_name, cert_pem.as_ref(), key_pem.as_ref());
+        self
+    }
+
+    /// Asks clients for a certificate signed by one of the CAs in `ca_pem` (mTLS), it
+    /// reaches handlers through the `ClientCertificate` extractor.
+    #[cfg(feature = "tls")]
+    pub fn tls_client_auth<P: AsRef<std::path::Path>>(
+        mut self,
+        ca_pem: P,
+        client_auth: ClientAuth,
+    ) -> Self {
+        self.tls_settings()
+            .set_client_auth(ca_pem.as_ref(), client_auth);
+        self
+    }
+
+    /// How often the certificate files are checked for changes, a minute by default.
+    #[cfg(feature = "tls")]
+    pub fn tls_reload_interval(mut self, interval: Duration) -> Self {
+        self.tls_settings().reload_interval = interval;
+        self
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_settings(&mut self) -> &mut TlsSettings {
+        self.tls
+            .as_mut()
+            .expect("TLS isn't enabled, call bind_tls first")
+    }
+
+    /// Handle to stop the server from another thread once `run` is called. The server
+    /// is stopped for good, see `ServerHandle`.
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// Runs the server until `signal` returns, e.g. `move || { let _ = receiver.recv(); }`,
+    /// then shuts it down gracefully.
+    pub fn run_with_shutdown<Signal>(&mut self, signal: Signal) -> Result<()>
+    where
+        Signal: FnOnce() + Send + 'static,
+    {
+        let handle = self.handle();
+
+        thread::spawn(move || {
+            signal();
+            handle.shutdown();
+        });
+
+        self.run()
+    }
+
+    /// Enables or disables persistent connections, enabled by default.
+    pub fn keep_alive(mut self, enabled: bool) -> Self {
+        self.config.keep_alive = enabled;
+        self
+    }
+
+    /// How long an open connection may stay idle between requests before it's closed.
+    pub fn keep_alive_timeout(mut self, timeout: Duration) -> Self {
+        self.config.keep_alive_timeout = timeout;
+        self
+    }
+
+    /// How long a new connection may stay idle before the client starts sending its
+    /// first request, later requests are waited for `keep_alive_timeout`.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.config.idle_timeout = timeout;
+        self
+    }
+
+    /// Time the client has to send the request line and headers once it started the
+    /// request, slower clients get `408 Request Timeout`.
+    pub fn header_timeout(mut self, timeout: Duration) -> Self {
+        self.config.header_timeout = timeout;
+        self
+    }
+
+    /// Time the client has to send the whole body after the headers, slower clients get
+    /// `408 Request Timeout`.
+    pub fn body_timeout(mut self, timeout: Duration) -> Self {
+        self.config.body_timeout = timeout;
+        self
+    }
+
+    /// How long sending a part of the response may wait for the client to read, a
+    /// client which stops reading is disconnected after it.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    /// Longest request line in bytes, longer ones are answered with `414 URI Too Long`.
+    pub fn max_request_line(mut self, bytes: usize) -> Self {
+        self.config.limits.max_request_line = bytes;
+        self
+    }
+
+    /// Most headers a request may have, more are answered with
+    /// `431 Request Header Fields Too Large`.
+    pub fn max_headers(mut self, count: usize) -> Self {
+        self.config.limits.max_headers = count;
+        self
+    }
+
+    /// Most bytes all header lines of a request may take, more are answered with
+    /// `431 Request Header Fields Too Large`.
+    pub fn max_header_size(mut self, bytes: usize) -> Self {
+        self.config.limits.max_header_size = bytes;
+        self
+    }
+
+    /// Largest request body in bytes, 2 MiB by default. Larger bodies are answered with
+    /// `413 Content Too Large` before they are read.
+    pub fn max_body_size(mut self, bytes: usize) -> Self {
+        self.config.limits.max_body_size = bytes;
+        self
+    }
+
+    /// Maximum number of requests served over one connection before it's closed.
+    pub fn max_requests_per_connection(mut self, max_requests: usize) -> Self {
+        if max_requests == 0 {
+            panic!("Max requests per connection must be more than 0");
         }
+
+        self.config.max_requests_per_connection = max_requests;
+        self
     }
 
+    /// Serves requests until the server is shut down with a `ServerHandle`, then
+    /// waits for in-flight requests (up to the shutdown timeout) and returns.
     pub fn run(&mut self) -> Result<()> {
-        let tcp_listener: Arc<Mutex<TcpListener>> =
-            Arc::new(Mutex::new(TcpListener::bind(self.addr)?));
+        #[cfg(unix)]
+        if let Some(listeners) = self.activated_listeners()? {
+            return self.serve(listeners);
+        }
 
-        let endpoints = Arc::new(self.endpoints.clone());
-        let mut threads: Vec<JoinHandle<()>> = Vec::new();
+        let listeners = self.bind_listeners()?;
+        let served = self.serve(listeners);
+        self.remove_socket_files();
+
+        served
+    }
+
+    /// Serves requests on an already bound listener instead of the added addresses,
+    /// e.g. one bound to port 0 in a test.
+    pub fn run_on(&mut self, listener: TcpListener) -> Result<()> {
+        self.serve(vec![Listener::Tcp(listener)])
+    }
+
+    fn serve(&mut self, listeners: Vec<Listener>) -> Result<()> {
+        self.start_listening(&listeners)?;
+
+        let endpoints = self.endpoints.clone();
+        let config = self.config.clone();
+        let handle = self.handle();
+
+        let pool = ThreadPool::new(self.pool, move |connection| match connection {
+            Connection::Tcp(stream) => serve_connection(stream, &endpoints, &config, &handle),
+            #[cfg(feature = "tls")]
+            Connection::Tls(stream) => serve_connection(*stream, &endpoints, &config, &handle),
+            #[cfg(unix)]
+            Connection::Unix(stream) => serve_connection(stream, &endpoints, &config, &handle),
+        });
+
+        // Every listener has an acceptor thread of its own, all of them feed the pool.
+        thread::scope(|scope| {
+            for listener in &listeners {
+                let (pool, handle, config) = (&pool, &self.handle, &self.config);
+                let policy = self.backlog_policy;
+
+                scope.spawn(move || accept_connections(listener, pool, handle, config, policy));
+            }
+        });
+
+        if !pool.shutdown(self.config.shutdown_timeout) {
+            log::warn!("shutdown timeout expired, some requests weren't finished");
+        }
+
+        Ok(())
+    }
+
+    /// Serves requests on the tokio runtime it's awaited on. Every connection is a task,
+    /// so idle and slow clients don't hold a thread and the worker pool settings aren't
+    /// used. Blocking handlers and middlewares run on the runtime's blocking threads.
+    #[cfg(feature = "tokio")]
+    pub async fn run_async(&mut self) -> Result<()> {
+        #[cfg(unix)]
+        if let Some(listeners) = self.activated_listeners()? {
+            return self.serve_async(listeners).await;
+        }
+
+        let listeners = self.bind_listeners()?;
+        let served = self.serve_async(listeners).await;
+        self.remove_socket_files();
+
+        served
+    }
+
+    /// `run_on` for the async server.
+    #[cfg(feature = "tokio")]
+    pub async fn run_async_on(&mut self, listener: TcpListener) -> Result<()> {
+        self.serve_async(vec![Listener::Tcp(listener)]).await
+    }
+
+    #[cfg(feature = "tokio")]
+    async fn serve_async(&mut self, listeners: Vec<Listener>) -> Result<()> {
+        self.start_listening(&listeners)?;
 
-        for i in 1..self.threads_num {
-            let listener = Arc::clone(&tcp_listener);
-            let thread_endpoints = Arc::clone(&endpoints);
+        let listeners = listeners
+            .into_iter()
+            .map(AsyncListener::from_std)
+            .collect::<Result<Vec<_>>>()?;
 
-            let handle = thread::spawn(move || loop {
-                let stream = listener.lock().unwrap().incoming().next().unwrap().unwrap();
+        let endpoints = Arc::new(self.endpoints.clone());
+        let config = Arc::new(self.config.clone());
+        let mut connections = tokio::task::JoinSet::new();
 
-                println!("thread {i} handles request");
+        while !self.handle.is_shutting_down() {
+            let connection = match accept_any(&listeners).await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    log::warn!("failed to accept connection: {e}");
 
-                let request = parse_request(stream.try_clone().unwrap());
+                    if !is_connection_error(&e) {
+                        tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    }
 
-                if request.is_none() {
-                    return_response(
-                        stream,
-                        HttpResponse {
-                            status: HttpStatus::BadRequest,
-                            body: None,
-                        },
-                    );
                     continue;
                 }
-                let request = request.unwrap();
+            };
 
-                let endpoints_map = get_endpoints_map(&request.method, &thread_endpoints);
-                handle_request(request, endpoints_map, stream);
-            });
+            // The connection which woke the acceptor up on shutdown is dropped.
+            if self.handle.is_shutting_down() {
+                break;
+            }
+
+            while connections.try_join_next().is_some() {}
+
+            let endpoints = Arc::clone(&endpoints);
+            let config = Arc::clone(&config);
+            let handle = self.handle();
+
+            match connection {
+                AsyncConnection::Tcp(stream, client) => connections.spawn(serve_connection_async(
+                    stream,
+                    Some(client),
+                    endpoints,
+                    config,
+                    handle,
+                )),
+                #[cfg(feature = "tokio-tls")]
+                AsyncConnection::Tls(stream, acceptor) => connections.spawn(
+                    serve_tls_connection_async(stream, acceptor, endpoints, config, handle),
+                ),
+                #[cfg(unix)]
+                AsyncConnection::Unix(stream) => connections.spawn(serve_connection_async(
+                    stream, None, endpoints, config, handle,
+                )),
+            };
+        }
 
-            threads.push(handle);
+        let finished = tokio::time::timeout(self.config.shutdown_timeout, async {
+            while connections.join_next().await.is_some() {}
+        })
+        .await;
+
+        if finished.is_err() {
+            log::warn!("shutdown timeout expired, some requests weren't finished");
         }
 
-        for th in threads {
-            th.join().unwrap();
+        Ok(())
+    }
+
+    /// Tells the handle where the listeners are, so the acceptors can be woken up on
+    /// shutdown, and sets up the signal handling.
+    fn start_listening(&self, listeners: &[Listener]) -> Result<()> {
+        let local_addrs = listeners
+            .iter()
+            .map(Listener::local_addr)
+            .collect::<Result<_>>()?;
+        self.handle.set_local_addrs(local_addrs);
+
+        #[cfg(all(unix, feature = "signals"))]
+        if self.config.shutdown_on_signals {
+            crate::shutdown::shutdown_on_signals(self.handle())?;
         }
 
         Ok(())
     }
 
+    /// Sockets passed by the service manager, `None` unless socket activation is
+    /// enabled and the process was started by it.
+    #[cfg(unix)]
+    fn activated_listeners(&self) -> Result<Option<Vec<Listener>>> {
+        if !self.socket_activation {
+            return Ok(None);
+        }
+
+        crate::listener::listen_fds()
+    }
+
+    fn bind_listeners(&self) -> Result<Vec<Listener>> {
+        #[cfg(feature = "tls")]
+        let tls = self.tls.as_ref().map(TlsSettings::acceptor).transpose()?;
+
+        let listeners = self
+            .addrs
+            .iter()
+            .map(|addr| match addr {
+                BindAddr::Tcp(addrs) => Ok(Listener::Tcp(bind_tcp(addrs)?)),
+                #[cfg(feature = "tls")]
+                BindAddr::Tls(addrs) => {
+                    let acceptor = tls.clone().expect("bind_tls sets the certificates");
+
+                    Ok(Listener::Tls(bind_tcp(addrs)?, acceptor))
+                }
+                #[cfg(unix)]
+                BindAddr::Unix(path) => Ok(Listener::Unix(crate::listener::bind_unix(path)?)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &tls {
+            tls.watch_certificates(self.handle());
+        }
+
+        Ok(listeners)
+    }
+
+    /// Socket files of Unix domain socket listeners aren't needed once the server stops.
+    fn remove_socket_files(&self) {
+        #[cfg(unix)]
+        for addr in &self.addrs {
+            if let BindAddr::Unix(path) = addr {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
     pub fn get<Handler>(mut self, get_handler_info: Handler) -> Self
     where
         Handler: Fn() -> HttpHandlerInfo,
     {
         let handler_info = get_handler_info();
-        let _ = &self
-            .endpoints
-            .get_endpoints
-            .insert(handler_info.route, handler_info.handler);
+        add_endpoint(&mut self.endpoints.get_endpoints, handler_info);
         self
     }
 
@@ -106,10 +709,7 @@ impl<'a> WebApi<'a> {
         Handler: Fn() -> HttpHandlerInfo,
     {
         let handler_info = get_handler_info();
-        let _ = &self
-            .endpoints
-            .post_endpoints
-            .insert(handler_info.route, handler_info.handler);
+        add_endpoint(&mut self.endpoints.post_endpoints, handler_info);
         self
     }
 
@@ -118,10 +718,7 @@ impl<'a> WebApi<'a> {
         Handler: Fn() -> HttpHandlerInfo,
     {
         let handler_info = get_handler_info();
-        let _ = &self
-            .endpoints
-            .delete_endpoints
-            .insert(handler_info.route, handler_info.handler);
+        add_endpoint(&mut self.endpoints.delete_endpoints, handler_info);
         self
     }
 
@@ -130,22 +727,1589 @@ impl<'a> WebApi<'a> {
         Handler: Fn() -> HttpHandlerInfo,
     {
         let handler_info = get_handler_info();
-        let _ = &self
-            .endpoints
-            .put_endpoints
-            .insert(handler_info.route, handler_info.handler);
+        add_endpoint(&mut self.endpoints.put_endpoints, handler_info);
+        self
+    }
+
+    pub fn patch<Handler>(mut self, get_handler_info: Handler) -> Self
+    where
+        Handler: Fn() -> HttpHandlerInfo,
+    {
+        let handler_info = get_handler_info();
+        add_endpoint(&mut self.endpoints.patch_endpoints, handler_info);
+        self
+    }
+
+    /// Registers a handler without the `http_handler` macro, e.g. a closure taking
+    /// `HandlerRequest`. Panics for `HEAD`, `OPTIONS`, `TRACE` and `CONNECT`, `HEAD` and
+    /// `OPTIONS` are answered automatically.
+    pub fn route<H>(mut self, method: HttpMethod, route: &str, handler: H) -> Self
+    where
+        H: Handler + Send + Sync + 'static,
+    {
+        let router = match method {
+            HttpMethod::Get => &mut self.endpoints.get_endpoints,
+            HttpMethod::Post => &mut self.endpoints.post_endpoints,
+            HttpMethod::Delete => &mut self.endpoints.delete_endpoints,
+            HttpMethod::Put => &mut self.endpoints.put_endpoints,
+            HttpMethod::Patch => &mut self.endpoints.patch_endpoints,
+            HttpMethod::Head | HttpMethod::Options | HttpMethod::Trace | HttpMethod::Connect => {
+                panic!(
+                    "Handlers can't be registered for {} requests",
+                    method.as_str()
+                )
+            }
+        };
+
+        let handler_info = HttpHandlerInfo {
+            handler: Arc::new(handler),
+            route: route.to_string(),
+        };
+
+        add_endpoint(router, handler_info);
         self
     }
 }
 
+/// Hands connections accepted by the listener over to the pool until the server shuts
+/// down.
+fn accept_connections(
+    listener: &Listener,
+    pool: &ThreadPool<Connection>,
+    handle: &ServerHandle,
+    config: &ServerConfig,
+    policy: BacklogPolicy,
+) {
+    while !handle.is_shutting_down() {
+        let accepted = listener.accept();
+
+        // The connection which woke the acceptor up on shutdown is dropped.
+        if handle.is_shutting_down() {
+            break;
+        }
+
+        let connection = match accepted {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::warn!("failed to accept connection: {e}");
+
+                if !is_connection_error(&e) {
+                    thread::sleep(ACCEPT_ERROR_BACKOFF);
+                }
+
+                continue;
+            }
+        };
+
+        if let Err(connection) = pool.execute(connection, policy == BacklogPolicy::Queue) {
+            reject_connection(connection, config, policy);
+        }
+    }
+}
+
+/// Connection which doesn't fit into the full queue is either answered with `503` or
+/// closed right away. TLS connections are always closed, answering them would mean
+/// doing the handshake on the acceptor thread.
+fn reject_connection(connection: Connection, config: &ServerConfig, policy: BacklogPolicy) {
+    log::warn!("all workers are busy and the queue is full, rejecting connection");
+
+    if policy != BacklogPolicy::Reject {
+        return;
+    }
+
+    let started_at = Instant::now();
+    let timeout = Some(Duration::from_secs(REJECT_WRITE_TIMEOUT));
+
+    let (client, written) = match connection {
+        Connection::Tcp(mut stream) => {
+            let _ = stream.set_write_timeout(timeout);
+            (stream.peer_addr().ok(), write_unavailable(&mut stream))
+        }
+        #[cfg(feature = "tls")]
+        Connection::Tls(_) => return,
+        #[cfg(unix)]
+        Connection::Unix(mut stream) => {
+            let _ = stream.set_write_timeout(timeout);
+            (None, write_unavailable(&mut stream))
+        }
+    };
+
+    if let Ok(bytes) = written {
+        let status = HttpStatus::ServiceUnavailable.code();
+        log_rejected(config, client, None, started_at, status, bytes);
+    }
+}
+
+fn write_unavailable(stream: &mut impl Write) -> Result<usize> {
+    let response =
+        HttpResponse::new(HttpStatus::ServiceUnavailable, None).header(CONNECTION_HEADER, "close");
+
+    write_response(stream, response)
+}
+
+/// Conflicting routes are programming errors, so they fail right when the api is built.
+fn add_endpoint(router: &mut Router<HttpRequestHandler>, handler_info: HttpHandlerInfo) {
+    if let Err(conflict) = router.insert(&handler_info.route, handler_info.handler) {
+        panic!("{conflict}");
+    }
+}
+
+/// Runs the middleware chain and the handler. The path is normalized first, so
+/// middlewares see the path the router matches, `//admin` or `/%61dmin` as `/admin`.
+pub(crate) fn handle_request(
+    request: HttpRequest,
+    endpoints: &Endpoints,
+    matched_route: &Cell<Option<String>>,
+) -> HttpResponse {
+    match normalize_request(request) {
+        Ok(request) => Next::new(endpoints, matched_route).run(request),
+        Err(response) => response,
+    }
+}
+
+/// Routes request to the user defined handler and calls it.
+pub(crate) fn dispatch(
+    request: HttpRequest,
+    endpoints: &Endpoints,
+    matched_route: &Cell<Option<String>>,
+) -> HttpResponse {
+    match route_request(request, endpoints) {
+        Ok(matched) => {
+            matched_route.set(Some(matched.route));
+            matched.handler.handle(matched.request)
+        }
+        Err(response) => response,
+    }
+}
+
+/// Finds the user defined handler for the request, or the response when there isn't
+/// one to call. `HEAD` is served by `GET` handlers and `OPTIONS` is answered
+/// automatically with the methods registered for the path. The uri is routed as it is,
+/// `handle_request` normalizes it before the middlewares run.
+pub(crate) fn route_request(
+    mut request: HttpRequest,
+    endpoints: &Endpoints,
+) -> std::result::Result<MatchedHandler, HttpResponse> {
+    let router = get_endpoints_map(&request.method, endpoints);
+    let route_match = |uri: &str| router.and_then(|router| parse_route(router, uri));
+
+    // The other methods are only looked at when there's no handler for this one.
+    if let Some(route_match) = route_match(&request.uri) {
+        return Ok(match_handler(route_match, request, &endpoints.state));
+    }
+
+    let mut allowed_methods = get_allowed_methods(endpoints, &request.uri);
+
+    if allowed_methods.is_empty() && endpoints.trailing_slash != TrailingSlash::Strict {
+        if let Some(uri) = toggle_trailing_slash(&request.uri) {
+            let uri_allowed_methods = get_allowed_methods(endpoints, &uri);
+
+            if !uri_allowed_methods.is_empty() {
+                if endpoints.trailing_slash == TrailingSlash::Redirect {
+                    return Err(HttpResponse::new(HttpStatus::PermanentRedirect, None)
+                        .header(LOCATION_HEADER, &uri));
+                }
+
+                if let Some(route_match) = route_match(&uri) {
+                    request.uri = uri;
+                    return Ok(match_handler(route_match, request, &endpoints.state));
+                }
+
+                allowed_methods = uri_allowed_methods;
+            }
+        }
+    }
+
+    if allowed_methods.is_empty() {
+        return Err(HttpResponse::new(HttpStatus::NotFound, None));
+    }
+
+    if request.method == HttpMethod::Options {
+        return Err(
+            HttpResponse::new(HttpStatus::NoContent, None).header(ALLOW_HEADER, &allowed_methods)
+        );
+    }
+
+    Err(HttpResponse::new(HttpStatus::MethodNotAllowed, None)
+        .header(ALLOW_HEADER, &allowed_methods))
+}
+
 fn get_endpoints_map<'a>(
     method: &HttpMethod,
     endpoints: &'a Endpoints,
-) -> &'a HashMap<String, HttpRequestHandler> {
+) -> Option<&'a Router<HttpRequestHandler>> {
     match method {
-        HttpMethod::Get => &endpoints.get_endpoints,
-        HttpMethod::Post => &endpoints.post_endpoints,
-        HttpMethod::Delete => &endpoints.delete_endpoints,
-        HttpMethod::Put => &endpoints.put_endpoints,
+        HttpMethod::Get | HttpMethod::Head => Some(&endpoints.get_endpoints),
+        HttpMethod::Post => Some(&endpoints.post_endpoints),
+        HttpMethod::Delete => Some(&endpoints.delete_endpoints),
+        HttpMethod::Put => Some(&endpoints.put_endpoints),
+        HttpMethod::Patch => Some(&endpoints.patch_endpoints),
+        HttpMethod::Options | HttpMethod::Trace | HttpMethod::Connect => None,
+    }
+}
+
+/// Value of the `Allow` header for the uri, empty if no handler matches it. `OPTIONS *`
+/// lists every method the server has handlers for.
+fn get_allowed_methods(endpoints: &Endpoints, uri: &str) -> String {
+    let mut allowed_methods = vec![];
+
+    for method in ROUTABLE_METHODS {
+        let endpoints_map = get_endpoints_map(&method, endpoints).unwrap();
+
+        let is_allowed = if uri == "*" {
+            !endpoints_map.is_empty()
+        } else {
+            parse_route(endpoints_map, uri).is_some()
+        };
+
+        if is_allowed {
+            allowed_methods.push(method.as_str());
+        }
+
+        if is_allowed && method == HttpMethod::Get {
+            allowed_methods.push(HttpMethod::Head.as_str());
+        }
+    }
+
+    if !allowed_methods.is_empty() {
+        allowed_methods.push(HttpMethod::Options.as_str());
+    }
+
+    allowed_methods.join(", ")
+}
+
+/// Request with the path part of the uri normalized, 400 for paths with encoded
+/// separators.
+pub(crate) fn normalize_request(
+    mut request: HttpRequest,
+) -> std::result::Result<HttpRequest, HttpResponse> {
+    match normalize_uri(&request.uri) {
+        Some(uri) => {
+            request.uri = uri;
+            Ok(request)
+        }
+        None => Err(HttpResponse::new(HttpStatus::BadRequest, None)),
+    }
+}
+
+/// Normalizes the path part of the uri, `None` for paths with encoded separators.
+fn normalize_uri(uri: &str) -> Option<String> {
+    let (path, query) = match uri.find('?') {
+        Some(idx) => uri.split_at(idx),
+        None => (uri, ""),
+    };
+
+    normalize_path(path).map(|path| format!("{path}{query}"))
+}
+
+/// `/users/?page=2` becomes `/users?page=2` and vice versa, `None` for the root path.
+fn toggle_trailing_slash(uri: &str) -> Option<String> {
+    let (path, query) = match uri.find('?') {
+        Some(idx) => uri.split_at(idx),
+        None => (uri, ""),
+    };
+
+    if path == "/" || !path.starts_with('/') {
+        return None;
+    }
+
+    let path = match path.strip_suffix('/') {
+        Some(path) => path.to_string(),
+        None => format!("{path}/"),
+    };
+
+    Some(format!("{path}{query}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{
+        dispatch, handle_request, toggle_trailing_slash, BacklogPolicy, HandlerPanic, WebApi,
+    };
+    use crate::connection::serve_connection;
+    use crate::handler::HandlerRequest;
+    use crate::headers::Headers;
+    use crate::http_request::HttpRequest;
+    use crate::http_response::{HttpResponse, HttpStatus, Response};
+    use crate::listener::ListenAddr;
+    use crate::method_verb::HttpMethod;
+    use crate::middleware::Next;
+    use crate::shutdown::ServerHandle;
+
+    fn request(method: HttpMethod, uri: &str) -> HttpRequest {
+        HttpRequest {
+            method,
+            uri: uri.to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: Headers::new(),
+            body: None,
+            trailers: Headers::new(),
+            client_certificate: None,
+        }
+    }
+
+    fn authorized_request(uri: &str, token: Option<&str>) -> HttpRequest {
+        let mut request = request(HttpMethod::Get, uri);
+
+        if let Some(token) = token {
+            request.headers.insert("Authorization", token);
+        }
+
+        request
+    }
+
+    #[test]
+    fn closure_handlers_registered_with_route() {
+        let greeting = "hello".to_string();
+        let api = WebApi::new("127.0.0.1:0", 1).route(
+            HttpMethod::Get,
+            "/greet/{name}",
+            move |request: HandlerRequest| {
+                Response::ok(format!("{greeting} {}", request.route.get("name").unwrap()))
+            },
+        );
+
+        let response = dispatch(
+            request(HttpMethod::Get, "/greet/bob"),
+            &api.endpoints,
+            &Cell::default(),
+        );
+
+        assert_eq!(response.status, HttpStatus::Ok);
+        assert_eq!(response.body.as_deref(), Some("\"hello bob\"".as_bytes()));
+
+        let response = dispatch(
+            request(HttpMethod::Post, "/greet/bob"),
+            &api.endpoints,
+            &Cell::default(),
+        );
+
+        assert_eq!(response.status, HttpStatus::MethodNotAllowed);
+    }
+
+    #[test]
+    fn trailers_dont_reach_request_headers() {
+        let api = WebApi::new("127.0.0.1:0", 1)
+            .wrap(|request: HttpRequest, next: Next| {
+                if request.headers.get("Authorization") != Some("secret") {
+                    return HttpResponse::new(HttpStatus::Unauthorized, None);
+                }
+
+                next.run(request)
+            })
+            .route(HttpMethod::Post, "/upload", |_: HandlerRequest| {
+                HttpResponse::ok(Some("uploaded".to_string()))
+            });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let server = thread::spawn(move || {
+            serve_connection(stream, &api.endpoints, &api.config, &api.handle);
+        });
+
+        client
+            .write_all(
+                b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+                5\r\nhello\r\n0\r\nAuthorization: secret\r\n\r\n",
+            )
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    }
+
+    #[test]
+    fn toggle_trailing_slash_keeps_query() {
+        assert_eq!(
+            toggle_trailing_slash("/users/?page=2"),
+            Some("/users?page=2".to_string())
+        );
+        assert_eq!(toggle_trailing_slash("/users"), Some("/users/".to_string()));
+        assert_eq!(toggle_trailing_slash("/?page=2"), None);
+    }
+
+    #[test]
+    fn middlewares_run_in_order_and_may_short_circuit() {
+        let api = WebApi::new("127.0.0.1:0", 1)
+            .wrap(|request: HttpRequest, next: Next| next.run(request).header("X-Outer", "1"))
+            .wrap(|mut request: HttpRequest, next: Next| {
+                if request.headers.get("Authorization") != Some("secret") {
+                    return HttpResponse::new(HttpStatus::Unauthorized, None);
+                }
+
+                request.headers.insert("X-User", "admin");
+                next.run(request)
+            })
+            .route(HttpMethod::Get, "/me", |request: HandlerRequest| {
+                Response::ok(request.headers.get("X-User").map(str::to_string))
+            });
+
+        let response = Next::new(&api.endpoints, &Cell::default())
+            .run(authorized_request("/me", Some("secret")));
+
+        assert_eq!(response.status, HttpStatus::Ok);
+        assert_eq!(response.body.as_deref(), Some("\"admin\"".as_bytes()));
+        assert_eq!(response.headers.get("X-Outer"), Some("1"));
+
+        let response =
+            Next::new(&api.endpoints, &Cell::default()).run(authorized_request("/me", None));
+
+        assert_eq!(response.status, HttpStatus::Unauthorized);
+        assert_eq!(response.headers.get("X-Outer"), Some("1"));
+
+        let response = Next::new(&api.endpoints, &Cell::default())
+            .run(authorized_request("/missing", Some("secret")));
+
+        assert_eq!(response.status, HttpStatus::NotFound);
+        assert_eq!(response.headers.get("X-Outer"), Some("1"));
+    }
+
+    fn resource_api() -> WebApi {
+        WebApi::new("127.0.0.1:0", 1)
+            .route(HttpMethod::Get, "/items/{id}", |_: HandlerRequest| {
+                HttpResponse::ok(Some("item".to_string()))
+            })
+            .route(HttpMethod::Delete, "/items/{id}", |_: HandlerRequest| {
+                HttpResponse::new(HttpStatus::NoContent, None)
+            })
+            .route(HttpMethod::Post, "/items", |_: HandlerRequest| {
+                HttpResponse::new(HttpStatus::Created, None)
+            })
+    }
+
+    #[test]
+    fn head_is_served_by_get_handlers_without_body() {
+        let api = resource_api();
+        let response = dispatch(
+            request(HttpMethod::Head, "/items/1"),
+            &api.endpoints,
+            &Cell::default(),
+        );
+
+        assert_eq!(response.status, HttpStatus::Ok);
+
+        let responses = exchange(
+            resource_api(),
+            b"GET /items/1 HTTP/1.1\r\n\r\nHEAD /items/1 HTTP/1.1\r\nConnection: close\r\n\r\n",
+        );
+
+        assert!(responses.starts_with("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nitem"));
+        assert!(responses
+            .ends_with("itemHTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 4\r\n\r\n"));
+    }
+
+    #[test]
+    fn options_lists_the_methods_of_the_path() {
+        let api = resource_api();
+        let options = |uri: &str| {
+            dispatch(
+                request(HttpMethod::Options, uri),
+                &api.endpoints,
+                &Cell::default(),
+            )
+        };
+
+        let response = options("/items/1");
+        assert_eq!(response.status, HttpStatus::NoContent);
+        assert_eq!(
+            response.headers.get("Allow"),
+            Some("GET, HEAD, DELETE, OPTIONS")
+        );
+
+        let response = options("*");
+        assert_eq!(
+            response.headers.get("Allow"),
+            Some("GET, HEAD, POST, DELETE, OPTIONS")
+        );
+
+        assert_eq!(options("/missing").status, HttpStatus::NotFound);
+    }
+
+    #[test]
+    fn other_methods_of_a_path_get_405_with_allow() {
+        let api = resource_api();
+        let response = dispatch(
+            request(HttpMethod::Put, "/items/1"),
+            &api.endpoints,
+            &Cell::default(),
+        );
+
+        assert_eq!(response.status, HttpStatus::MethodNotAllowed);
+        assert_eq!(
+            response.headers.get("Allow"),
+            Some("GET, HEAD, DELETE, OPTIONS")
+        );
+
+        let response = dispatch(
+            request(HttpMethod::Post, "/items"),
+            &api.endpoints,
+            &Cell::default(),
+        );
+        assert_eq!(response.status, HttpStatus::Created);
+    }
+
+    #[test]
+    fn middlewares_see_normalized_paths() {
+        let api = WebApi::new("127.0.0.1:0", 1)
+            .wrap(|request: HttpRequest, next: Next| {
+                if request.uri.starts_with("/admin") {
+                    return HttpResponse::new(HttpStatus::Forbidden, None);
+                }
+
+                next.run(request)
+            })
+            .route(HttpMethod::Get, "/admin", |_: HandlerRequest| {
+                HttpResponse::ok(Some("secret".to_string()))
+            });
+
+        for uri in [
+            "/admin",
+            "//admin",
+            "/x/../admin",
+            "/%61dmin",
+            "/./admin?a=b",
+        ] {
+            let response = handle_request(
+                request(HttpMethod::Get, uri),
+                &api.endpoints,
+                &Cell::default(),
+            );
+
+            assert_eq!(response.status, HttpStatus::Forbidden, "{uri}");
+        }
+
+        let response = handle_request(
+            request(HttpMethod::Get, "/x/..%2Fadmin"),
+            &api.endpoints,
+            &Cell::default(),
+        );
+
+        assert_eq!(response.status, HttpStatus::BadRequest);
+    }
+
+    #[test]
+    fn handler_panics_are_answered_with_500() {
+        let reported = Arc::new(Mutex::new(vec![]));
+        let hook_reported = Arc::clone(&reported);
+        let api = WebApi::new("127.0.0.1:0", 1)
+            .on_panic(move |panic: &HandlerPanic| {
+                hook_reported.lock().unwrap().push(panic.message.clone())
+            })
+            .route(
+                HttpMethod::Get,
+                "/panic",
+                |_: HandlerRequest| -> HttpResponse { panic!("handler failed") },
+            )
+            .route(HttpMethod::Get, "/ok", |_: HandlerRequest| {
+                HttpResponse::ok(Some("ok".to_string()))
+            });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let server = thread::spawn(move || {
+            serve_connection(stream, &api.endpoints, &api.config, &api.handle);
+        });
+
+        client
+            .write_all(b"GET /panic HTTP/1.1\r\n\r\nGET /ok HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        server.join().unwrap();
+
+        assert!(responses.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(responses.contains("HTTP/1.1 200 OK\r\n"));
+        assert!(responses.ends_with("\r\n\r\nok"));
+        assert_eq!(
+            *reported.lock().unwrap(),
+            vec!["handler failed".to_string()]
+        );
+    }
+
+    #[test]
+    fn chunked_body_panics_are_reported() {
+        let reported = Arc::new(Mutex::new(vec![]));
+        let hook_reported = Arc::clone(&reported);
+        let api = WebApi::new("127.0.0.1:0", 1)
+            .on_panic(move |panic: &HandlerPanic| {
+                hook_reported.lock().unwrap().push(panic.message.clone())
+            })
+            .route(HttpMethod::Get, "/stream", |_: HandlerRequest| {
+                let chunks = (0..3).map(|i| match i {
+                    2 => panic!("chunk failed"),
+                    _ => format!("chunk {i}"),
+                });
+
+                HttpResponse::chunked(HttpStatus::Ok, chunks)
+            });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let server = thread::spawn(move || {
+            serve_connection(stream, &api.endpoints, &api.config, &api.handle);
+        });
+
+        client
+            .write_all(b"GET /stream HTTP/1.1\r\n\r\nGET /stream HTTP/1.1\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+
+        // The connection is closed without the last chunk, before the pipelined request.
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("7\r\nchunk 0\r\n7\r\nchunk 1\r\n"));
+        assert_eq!(response.matches("HTTP/1.1").count(), 1);
+        assert_eq!(*reported.lock().unwrap(), vec!["chunk failed".to_string()]);
+    }
+
+    #[test]
+    fn http_1_0_chunked_body_panics_are_answered_with_500() {
+        let api = http_1_0_panicking_stream_api();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let server = thread::spawn(move || {
+            serve_connection(stream, &api.endpoints, &api.config, &api.handle);
+        });
+
+        client.write_all(HTTP_1_0_STREAM_REQUESTS).unwrap();
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        server.join().unwrap();
+
+        assert!(responses.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(!responses.contains("chunk 0"));
+        assert!(responses.ends_with("\r\n\r\nok"));
+    }
+
+    /// Requests for `http_1_0_panicking_stream_api`, the connection is kept alive after
+    /// the first one.
+    const HTTP_1_0_STREAM_REQUESTS: &[u8] =
+        b"GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /ok HTTP/1.0\r\n\r\n";
+
+    /// The chunked body of `/stream` panics while it's collected for HTTP/1.0 clients.
+    fn http_1_0_panicking_stream_api() -> WebApi {
+        WebApi::new("127.0.0.1:0", 1)
+            .route(HttpMethod::Get, "/stream", |_: HandlerRequest| {
+                let chunks = (0..3).map(|i| match i {
+                    2 => panic!("chunk failed"),
+                    _ => format!("chunk {i}"),
+                });
+
+                HttpResponse::chunked(HttpStatus::Ok, chunks)
+            })
+            .route(HttpMethod::Get, "/ok", |_: HandlerRequest| {
+                HttpResponse::ok(Some("ok".to_string()))
+            })
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_server_answers_http_1_0_chunked_body_panics_with_500() {
+        use crate::async_connection::serve_connection_async;
+
+        let api = http_1_0_panicking_stream_api();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let server = thread::spawn(move || {
+            runtime.block_on(async move {
+                let stream = tokio::net::TcpStream::from_std(stream).unwrap();
+                let (endpoints, config) = (Arc::new(api.endpoints), Arc::new(api.config));
+
+                serve_connection_async(stream, None, endpoints, config, api.handle).await;
+            })
+        });
+
+        client.write_all(HTTP_1_0_STREAM_REQUESTS).unwrap();
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        server.join().unwrap();
+
+        assert!(responses.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(!responses.contains("chunk 0"));
+        assert!(responses.ends_with("\r\n\r\nok"));
+    }
+
+    #[test]
+    fn invalid_custom_status_is_answered_with_500() {
+        let api = WebApi::new("127.0.0.1:0", 1).route(HttpMethod::Get, "/", |_: HandlerRequest| {
+            HttpResponse::new(
+                HttpStatus::Custom(599, "Oops\r\nX-Injected: 1".to_string()),
+                None,
+            )
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let server = thread::spawn(move || {
+            serve_connection(stream, &api.endpoints, &api.config, &api.handle);
+        });
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        server.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(!response.contains("X-Injected"));
+    }
+
+    #[test]
+    fn slow_requests_time_out_with_408() {
+        let api = WebApi::new("127.0.0.1:0", 1)
+            .header_timeout(Duration::from_millis(200))
+            .route(HttpMethod::Get, "/", |_: HandlerRequest| {
+                HttpResponse::ok(None)
+            });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let server = thread::spawn(move || {
+            serve_connection(stream, &api.endpoints, &api.config, &api.handle);
+        });
+
+        // Headers keep trickling in, but never end.
+        client.write_all(b"GET / HTTP/1.1\r\n").unwrap();
+
+        for _ in 0..5 {
+            if client.write_all(b"X-Slow: 1\r\n").is_err() {
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let mut response = String::new();
+        let _ = client.read_to_string(&mut response);
+        server.join().unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn clients_which_stop_reading_are_disconnected() {
+        let api = WebApi::new("127.0.0.1:0", 1)
+            .write_timeout(Duration::from_millis(200))
+            .route(HttpMethod::Get, "/", |_: HandlerRequest| {
+                HttpResponse::bytes(HttpStatus::Ok, vec![0; 64 * 1024 * 1024])
+            });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let (finished, server_finished) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            serve_connection(stream, &api.endpoints, &api.config, &api.handle);
+            finished.send(()).unwrap();
+        });
+
+        // The response is far larger than the socket buffers and is never read.
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+
+        assert!(server_finished.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
+
+    #[test]
+    fn run_on_serves_prebound_listener() {
+        let mut api =
+            WebApi::new("127.0.0.1:0", 1).route(HttpMethod::Get, "/ping", |_: HandlerRequest| {
+                HttpResponse::ok(Some("pong".to_string()))
+            });
+        let handle = api.handle();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || api.run_on(listener));
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client
+            .write_all(b"GET /ping HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        assert!(response.ends_with("\r\n\r\npong"));
+        assert_eq!(handle.local_addrs(), vec![ListenAddr::Tcp(addr)]);
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    /// Sends `requests` on one connection and returns everything the server wrote
+    /// until it closed the connection.
+    fn exchange(api: WebApi, requests: &[u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let server = thread::spawn(move || {
+            serve_connection(stream, &api.endpoints, &api.config, &api.handle);
+        });
+
+        client.write_all(requests).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        server.join().unwrap();
+
+        responses
+    }
+
+    fn ping_api() -> WebApi {
+        WebApi::new("127.0.0.1:0", 1).route(HttpMethod::Get, "/ping", |_: HandlerRequest| {
+            HttpResponse::ok(Some("pong".to_string()))
+        })
+    }
+
+    #[test]
+    fn http_1_1_connections_stay_open_until_close_is_asked_for() {
+        let responses = exchange(
+            ping_api(),
+            b"GET /ping HTTP/1.1\r\n\r\nGET /ping HTTP/1.1\r\nConnection: close\r\n\r\nGET /ping HTTP/1.1\r\n\r\n",
+        );
+
+        assert_eq!(responses.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert_eq!(responses.matches("Connection: close\r\n").count(), 1);
+        assert!(responses.ends_with("Connection: close\r\nContent-Length: 4\r\n\r\npong"));
+    }
+
+    #[test]
+    fn http_1_0_connections_stay_open_only_with_keep_alive() {
+        let responses = exchange(
+            ping_api(),
+            b"GET /ping HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /ping HTTP/1.0\r\n\r\nGET /ping HTTP/1.0\r\n\r\n",
+        );
+
+        assert_eq!(responses.matches("HTTP/1.1 200 OK\r\n").count(), 2);
+        assert!(responses.starts_with("HTTP/1.1 200 OK\r\nConnection: keep-alive\r\n"));
+        assert!(responses.ends_with("Connection: close\r\nContent-Length: 4\r\n\r\npong"));
+    }
+
+    #[test]
+    fn connections_close_after_max_requests() {
+        let requests = b"GET /ping HTTP/1.1\r\n\r\n".repeat(4);
+        let responses = exchange(ping_api().max_requests_per_connection(3), &requests);
+
+        assert_eq!(responses.matches("HTTP/1.1 200 OK\r\n").count(), 3);
+        assert_eq!(responses.matches("Connection: close\r\n").count(), 1);
+        assert!(responses.ends_with("Connection: close\r\nContent-Length: 4\r\n\r\npong"));
+    }
+
+    /// Address of the first listener once `run` has bound it.
+    fn wait_for_addr(handle: &ServerHandle) -> std::net::SocketAddr {
+        loop {
+            match handle.local_addrs().first() {
+                Some(ListenAddr::Tcp(addr)) => return *addr,
+                _ => thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    #[test]
+    fn single_worker_serves_one_connection_after_another() {
+        let mut api =
+            WebApi::new("127.0.0.1:0", 1).route(HttpMethod::Get, "/ping", |_: HandlerRequest| {
+                HttpResponse::ok(Some("pong".to_string()))
+            });
+        let handle = api.handle();
+        let server = thread::spawn(move || api.run());
+        let addr = wait_for_addr(&handle);
+
+        for _ in 0..3 {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /ping HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+
+            assert!(response.ends_with("\r\n\r\npong"));
+        }
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn every_bound_address_is_served() {
+        let mut api = WebApi::new("127.0.0.1:0", 1).bind("127.0.0.1:0").route(
+            HttpMethod::Get,
+            "/ping",
+            |_: HandlerRequest| HttpResponse::ok(Some("pong".to_string())),
+        );
+        let handle = api.handle();
+        let server = thread::spawn(move || api.run());
+
+        let addrs = loop {
+            match handle.local_addrs().as_slice() {
+                [ListenAddr::Tcp(first), ListenAddr::Tcp(second)] => break [*first, *second],
+                _ => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        assert_ne!(addrs[0], addrs[1]);
+
+        for addr in addrs {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client
+                .write_all(b"GET /ping HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+
+            let mut response = String::new();
+            client.read_to_string(&mut response).unwrap();
+
+            assert!(response.ends_with("\r\n\r\npong"), "{addr}");
+        }
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn run_with_shutdown_finishes_in_flight_requests() {
+        let (started, wait_for_start) = std::sync::mpsc::channel::<()>();
+        let (release, wait_for_release) = std::sync::mpsc::channel::<()>();
+        let handler_channels = Mutex::new((started, wait_for_release));
+
+        let mut api = WebApi::new("127.0.0.1:0", 1).route(
+            HttpMethod::Get,
+            "/slow",
+            move |_: HandlerRequest| {
+                let channels = handler_channels.lock().unwrap();
+                channels.0.send(()).unwrap();
+                channels.1.recv_timeout(Duration::from_secs(5)).unwrap();
+
+                HttpResponse::ok(Some("finished".to_string()))
+            },
+        );
+        let handle = api.handle();
+
+        // Shuts the server down as soon as the request is being handled.
+        let server = thread::spawn(move || {
+            api.run_with_shutdown(move || {
+                let _ = wait_for_start.recv();
+            })
+        });
+
+        let mut client = TcpStream::connect(wait_for_addr(&handle)).unwrap();
+        client.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+
+        while !handle.is_shutting_down() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        release.send(()).unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+
+        // The connection is closed after the response.
+        assert!(response.ends_with("\r\n\r\nfinished"));
+        server.join().unwrap().unwrap();
+    }
+
+    /// Serves one worker and no queue, the worker is kept busy by a request to `/busy`
+    /// until the returned sender is used. Returns the client connection of that request.
+    fn busy_server(
+        policy: BacklogPolicy,
+    ) -> (
+        std::net::SocketAddr,
+        TcpStream,
+        std::sync::mpsc::Sender<()>,
+        ServerHandle,
+        thread::JoinHandle<std::io::Result<()>>,
+    ) {
+        let (started, wait_for_start) = std::sync::mpsc::channel::<()>();
+        let (release, wait_for_release) = std::sync::mpsc::channel::<()>();
+        let handler_channels = Mutex::new((started, wait_for_release));
+
+        let mut api = WebApi::new("127.0.0.1:0", 1)
+            .queue_capacity(0)
+            .backlog_policy(policy)
+            .route(HttpMethod::Get, "/busy", move |_: HandlerRequest| {
+                let channels = handler_channels.lock().unwrap();
+                channels.0.send(()).unwrap();
+                channels.1.recv_timeout(Duration::from_secs(5)).unwrap();
+
+                HttpResponse::ok(Some("done".to_string()))
+            });
+        let handle = api.handle();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || api.run_on(listener));
+
+        let mut busy = TcpStream::connect(addr).unwrap();
+        busy.write_all(b"GET /busy HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        wait_for_start.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        (addr, busy, release, handle, server)
+    }
+
+    #[test]
+    fn full_queue_rejects_connections_with_503() {
+        let (addr, mut busy, release, handle, server) = busy_server(BacklogPolicy::Reject);
+
+        let mut rejected = TcpStream::connect(addr).unwrap();
+        let mut response = String::new();
+        rejected.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+
+        release.send(()).unwrap();
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+
+        assert!(response.ends_with("\r\n\r\ndone"));
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn full_queue_drops_connections() {
+        let (addr, mut busy, release, handle, server) = busy_server(BacklogPolicy::Drop);
+
+        let mut dropped = TcpStream::connect(addr).unwrap();
+        dropped
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut response = Vec::new();
+
+        // Closed without a response, possibly with a reset.
+        match dropped.read_to_end(&mut response) {
+            Ok(read) => assert_eq!(read, 0),
+            Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+        }
+
+        release.send(()).unwrap();
+        let mut response = String::new();
+        busy.read_to_string(&mut response).unwrap();
+
+        assert!(response.ends_with("\r\n\r\ndone"));
+
+        handle.shutdown();
+        server.join().unwrap().unwrap();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_server_awaits_async_handlers() {
+        use crate::async_connection::serve_connection_async;
+        use crate::handler::AsyncHandler;
+
+        let api = WebApi::new("127.0.0.1:0", 1)
+            .route(
+                HttpMethod::Get,
+                "/async",
+                AsyncHandler(|request: HandlerRequest| async move {
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                    HttpResponse::ok(request.query.get("name").map(str::to_string))
+                }),
+            )
+            .route(HttpMethod::Get, "/blocking", |_: HandlerRequest| {
+                HttpResponse::ok(Some("blocking".to_string()))
+            });
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let server = thread::spawn(move || {
+            runtime.block_on(async move {
+                let stream = tokio::net::TcpStream::from_std(stream).unwrap();
+                let (endpoints, config) = (Arc::new(api.endpoints), Arc::new(api.config));
+
+                serve_connection_async(stream, None, endpoints, config, api.handle).await;
+            })
+        });
+
+        // The first request arrives in two parts.
+        client
+            .write_all(b"GET /async?name=ligma HTTP/1.1\r\nHo")
+            .unwrap();
+        thread::sleep(std::time::Duration::from_millis(50));
+        client
+            .write_all(b"st: x\r\n\r\nGET /blocking HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        server.join().unwrap();
+
+        assert!(responses.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(responses.contains("\r\n\r\nligmaHTTP/1.1 200 OK\r\n"));
+        assert!(responses.ends_with("\r\n\r\nblocking"));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_server_awaits_async_handlers_behind_middlewares() {
+        use crate::async_connection::serve_connection_async;
+        use crate::handler::AsyncHandler;
+
+        let api = WebApi::new("127.0.0.1:0", 1)
+            .wrap(|request: HttpRequest, next: Next| next.run(request).header("X-Wrapped", "1"))
+            .route(
+                HttpMethod::Get,
+                "/sleep",
+                AsyncHandler(|_: HandlerRequest| async move {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    HttpResponse::ok(Some("slept".to_string()))
+                }),
+            );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let server = thread::spawn(move || {
+            runtime.block_on(async move {
+                let stream = tokio::net::TcpStream::from_std(stream).unwrap();
+                let (endpoints, config) = (Arc::new(api.endpoints), Arc::new(api.config));
+
+                serve_connection_async(stream, None, endpoints, config, api.handle).await;
+            })
+        });
+
+        client
+            .write_all(
+                b"GET /sleep HTTP/1.1\r\n\r\nGET /sleep HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut responses = String::new();
+        client.read_to_string(&mut responses).unwrap();
+        server.join().unwrap();
+
+        assert_eq!(responses.matches("X-Wrapped: 1\r\n").count(), 2);
+        assert_eq!(responses.matches("\r\n\r\nslept").count(), 2);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_server_streams_chunked_bodies() {
+        use std::sync::mpsc;
+
+        use crate::async_connection::serve_connection_async;
+
+        // The second chunk is only produced after the client has read the first one.
+        let (first_read, wait_for_client) = mpsc::channel::<()>();
+        let wait_for_client = Mutex::new(Some(wait_for_client));
+
+        let api = WebApi::new("127.0.0.1:0", 1).route(
+            HttpMethod::Get,
+            "/stream",
+            move |_: HandlerRequest| {
+                let wait_for_client = wait_for_client.lock().unwrap().take().unwrap();
+                let chunks = (0..2).map(move |i| {
+                    if i == 1 {
+                        wait_for_client
+                            .recv_timeout(Duration::from_secs(5))
+                            .unwrap();
+                    }
+
+                    format!("chunk {i}")
+                });
+
+                HttpResponse::chunked(HttpStatus::Ok, chunks)
+            },
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let server = thread::spawn(move || {
+            runtime.block_on(async move {
+                let stream = tokio::net::TcpStream::from_std(stream).unwrap();
+                let (endpoints, config) = (Arc::new(api.endpoints), Arc::new(api.config));
+
+                serve_connection_async(stream, None, endpoints, config, api.handle).await;
+            })
+        });
+
+        client
+            .write_all(b"GET /stream HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut response = Vec::new();
+        let mut buffer = [0; 1024];
+
+        while !response.ends_with(b"7\r\nchunk 0\r\n") {
+            let read = client.read(&mut buffer).unwrap();
+            assert_ne!(read, 0);
+            response.extend_from_slice(&buffer[..read]);
+        }
+
+        first_read.send(()).unwrap();
+        client.read_to_end(&mut response).unwrap();
+        server.join().unwrap();
+
+        let response = String::from_utf8(response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("7\r\nchunk 0\r\n7\r\nchunk 1\r\n0\r\n\r\n"));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn async_server_reads_bodies_arriving_in_small_parts() {
+        use std::pin::Pin;
+        use std::task::{Context, Poll};
+
+        use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+        use crate::async_connection::serve_connection_async;
+
+        /// Hands out the requests a few bytes per read, cutting through every line.
+        struct Trickle {
+            input: Vec<u8>,
+            position: usize,
+            output: Vec<u8>,
+        }
+
+        impl AsyncRead for Trickle {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                let end = (self.position + 7).min(self.input.len());
+                let end = end.min(self.position + buf.remaining());
+                buf.put_slice(&self.input[self.position..end]);
+                self.position = end;
+
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        impl AsyncWrite for Trickle {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<std::io::Result<usize>> {
+                self.output.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_shutdown(
+                self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        let api = WebApi::new("127.0.0.1:0", 1).route(
+            HttpMethod::Post,
+            "/upload",
+            |request: HandlerRequest| {
+                let body = request.body.unwrap_or_default();
+                let is_intact = body.iter().enumerate().all(|(i, byte)| *byte == i as u8);
+
+                HttpResponse::ok(Some(format!("{} {is_intact};", body.len())))
+            },
+        );
+
+        let body: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let mut input = format!(
+            "POST /upload HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        input.extend_from_slice(&body);
+        input.extend_from_slice(
+            b"POST /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n",
+        );
+
+        for chunk in body.chunks(1000) {
+            input.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
+            input.extend_from_slice(chunk);
+            input.extend_from_slice(b"\r\n");
+        }
+
+        input.extend_from_slice(b"0\r\n\r\n");
+
+        let mut stream = Trickle {
+            input,
+            position: 0,
+            output: Vec::new(),
+        };
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(async {
+                let (endpoints, config) = (Arc::new(api.endpoints), Arc::new(api.config));
+
+                serve_connection_async(&mut stream, None, endpoints, config, api.handle).await;
+            });
+
+        let responses = String::from_utf8(stream.output).unwrap();
+
+        assert!(responses.contains("\r\n\r\n100000 true;HTTP/1.1 200 OK\r\n"));
+        assert!(responses.ends_with("\r\n\r\n100000 true;"));
+    }
+
+    /// CA, server certificates for `localhost` and `*.example.com` and a client
+    /// certificate, written as PEM files into a directory of their own.
+    #[cfg(feature = "tls")]
+    fn tls_files(name: &str) -> std::path::PathBuf {
+        use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+        let dir = std::env::temp_dir().join(format!("sugondese-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        std::fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+        for (file, names) in [
+            ("localhost", vec!["localhost".to_string()]),
+            ("example", vec!["*.example.com".to_string()]),
+            ("client", vec![]),
+        ] {
+            let key = KeyPair::generate().unwrap();
+            let certificate = CertificateParams::new(names)
+                .unwrap()
+                .signed_by(&key, &ca, &ca_key)
+                .unwrap();
+
+            std::fs::write(dir.join(format!("{file}.pem")), certificate.pem()).unwrap();
+            std::fs::write(dir.join(format!("{file}.key")), key.serialize_pem()).unwrap();
+        }
+
+        dir
+    }
+
+    /// Sends `GET /` over TLS to `serve_connection`, returns the response and the
+    /// certificate the server presented, or the error of a failed handshake.
+    #[cfg(feature = "tls")]
+    fn tls_get(
+        api: WebApi,
+        dir: &std::path::Path,
+        server_name: &str,
+        client_certificate: bool,
+    ) -> std::io::Result<(String, Vec<u8>)> {
+        use rustls::crypto::ring;
+        use rustls::pki_types::pem::PemObject;
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+        use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+        let acceptor = api.tls.as_ref().unwrap().acceptor().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let server = thread::spawn(move || {
+            let stream = acceptor.accept(stream).unwrap();
+            serve_connection(stream, &api.endpoints, &api.config, &api.handle);
+        });
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from_pem_file(dir.join("ca.pem")).unwrap())
+            .unwrap();
+
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = if client_certificate {
+            let chain = vec![CertificateDer::from_pem_file(dir.join("client.pem")).unwrap()];
+            let key = PrivateKeyDer::from_pem_file(dir.join("client.key")).unwrap();
+
+            config.with_client_auth_cert(chain, key).unwrap()
+        } else {
+            config.with_no_client_auth()
+        };
+
+        let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+        let connection = ClientConnection::new(Arc::new(config), server_name).unwrap();
+        let mut stream = StreamOwned::new(connection, client);
+
+        let mut response = String::new();
+        let read = stream
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .and_then(|_| stream.read_to_string(&mut response))
+            .map(|_| {
+                let certificates = stream.conn.peer_certificates().unwrap();
+                (response, certificates[0].to_vec())
+            });
+
+        drop(stream);
+        server.join().unwrap();
+
+        read
+    }
+
+    #[cfg(feature = "tls")]
+    fn pem_certificate(path: &std::path::Path) -> Vec<u8> {
+        use rustls::pki_types::pem::PemObject;
+        use rustls::pki_types::CertificateDer;
+
+        CertificateDer::from_pem_file(path).unwrap().to_vec()
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_listener_picks_certificates_by_server_name() {
+        let dir = tls_files("tls-sni");
+        let api = || {
+            WebApi::new("127.0.0.1:0", 1)
+                .bind_tls(
+                    "127.0.0.1:0",
+                    dir.join("localhost.pem"),
+                    dir.join("localhost.key"),
+                )
+                .tls_certificate(
+                    "*.example.com",
+                    dir.join("example.pem"),
+                    dir.join("example.key"),
+                )
+                .route(HttpMethod::Get, "/", |_: HandlerRequest| {
+                    HttpResponse::ok(Some("secure".to_string()))
+                })
+        };
+
+        let (response, certificate) = tls_get(api(), &dir, "localhost", false).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nsecure"));
+        assert_eq!(certificate, pem_certificate(&dir.join("localhost.pem")));
+
+        let (response, certificate) = tls_get(api(), &dir, "api.example.com", false).unwrap();
+
+        assert!(response.ends_with("\r\n\r\nsecure"));
+        assert_eq!(certificate, pem_certificate(&dir.join("example.pem")));
+
+        // No certificate matches, the default one fails the client's name check.
+        assert!(tls_get(api(), &dir, "example.org", false).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_client_certificates_reach_handlers() {
+        use crate::tls::ClientAuth;
+
+        let dir = tls_files("tls-client-auth");
+        let client_certificate = pem_certificate(&dir.join("client.pem"));
+        let api = |client_auth| {
+            let client_certificate = client_certificate.clone();
+
+            WebApi::new("127.0.0.1:0", 1)
+                .bind_tls(
+                    "127.0.0.1:0",
+                    dir.join("localhost.pem"),
+                    dir.join("localhost.key"),
+                )
+                .tls_client_auth(dir.join("ca.pem"), client_auth)
+                .route(HttpMethod::Get, "/", move |request: HandlerRequest| {
+                    let presented = match request.client_certificate {
+                        Some(certificate) => certificate.0 == client_certificate,
+                        None => false,
+                    };
+
+                    HttpResponse::ok(Some(format!("presented {presented}")))
+                })
+        };
+
+        let (response, _) = tls_get(api(ClientAuth::Optional), &dir, "localhost", true).unwrap();
+        assert!(response.ends_with("\r\n\r\npresented true"));
+
+        let (response, _) = tls_get(api(ClientAuth::Optional), &dir, "localhost", false).unwrap();
+        assert!(response.ends_with("\r\n\r\npresented false"));
+
+        let (response, _) = tls_get(api(ClientAuth::Required), &dir, "localhost", true).unwrap();
+        assert!(response.ends_with("\r\n\r\npresented true"));
+
+        assert!(tls_get(api(ClientAuth::Required), &dir, "localhost", false).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    #[should_panic(expected = "bind_tls can be called only once")]
+    fn bind_tls_twice_panics() {
+        let _ = WebApi::new("127.0.0.1:0", 1)
+            .bind_tls("127.0.0.1:0", "a.pem", "a.key")
+            .bind_tls("127.0.0.1:0", "b.pem", "b.key");
     }
 }